@@ -1,24 +1,105 @@
 /* ================= AST ================= */
 
+use crate::lexer::Span;
+
 #[derive(Debug, Clone)]
 pub enum Expr {
     Number(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Nil,
     Var(String),
     Add(Box<Expr>, Box<Expr>),
-    Call(String, Vec<Expr>),
+    Concat(Box<Expr>, Box<Expr>),
+    /// `{ a, b, k = v, [e] = v }`: positional values, then keyed entries.
+    Table(Vec<Expr>, Vec<(Expr, Expr)>),
+    /// `t[k]`, and `t.name` as `t["name"]`.
+    Index(Box<Expr>, Box<Expr>),
+    Compare(CmpOp, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// `fn (params) { body }`; the name is left empty.
+    Function(Function),
+    /// Callee and arguments; a plain name calls the variable, script
+    /// function or native of that name. The span runs to the `)`.
+    Call(Box<Expr>, Vec<Expr>, Span),
+    /// `...`, the extra arguments of a variadic function.
+    Vararg,
+    /// A call or `...` in parentheses, cut down to its first value.
+    Paren(Box<Expr>),
+}
+
+impl Expr {
+    /// Operands of the expression; a function's body is not among them.
+    pub fn operands(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Add(a, b) | Expr::Concat(a, b) | Expr::Compare(_, a, b) | Expr::And(a, b) | Expr::Or(a, b)
+            | Expr::Index(a, b) => vec![a, b],
+            Expr::Not(a) | Expr::Paren(a) => vec![a],
+            Expr::Table(items, fields) => items.iter().chain(fields.iter().flat_map(|(k, v)| [k, v])).collect(),
+            Expr::Call(f, args, _) => std::iter::once(&**f).chain(args).collect(),
+            _ => Vec::new(),
+        }
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp { Eq, Ne, Lt, Le, Gt, Ge }
+
+/// Statements but blocks and function definitions end in the span of the
+/// whole statement.
 #[derive(Debug, Clone)]
 pub enum Stmt {
-    Assign(String, Expr),
-    Print(Expr),
-    Return(Expr),
+    Assign(String, Expr, Span),
+    SetIndex(Expr, Expr, Expr, Span), // t[k] = v
+    /// `x, y = a, b`; a call or `...` last on the right spreads over the
+    /// remaining targets, values past the last target are dropped.
+    MultiAssign(Vec<String>, Vec<Expr>, Span),
+    /// `let x, y = a, b;`, or `let x;` for nil: new variables for the rest of
+    /// the block, shadowing any of the same names, even of the same block.
+    /// The values are evaluated before the names come into scope.
+    Let(Vec<String>, Vec<Expr>, Span),
+    /// `{ ... }`, a scope for the `let`s in it.
+    Block(Vec<Stmt>),
+    Print(Expr, Span),
+    /// A call made for its effects; its results are dropped.
+    Expr(Expr, Span),
+    /// `return a, b;`, or no values for a bare `return;`.
+    Return(Vec<Expr>, Span),
     FunctionDef(Function),
 }
 
+impl Stmt {
+    /// Expressions directly in the statement, not in blocks or functions it holds.
+    pub fn exprs(&self) -> Vec<&Expr> {
+        match self {
+            Stmt::Assign(_, e, _) | Stmt::Print(e, _) | Stmt::Expr(e, _) => vec![e],
+            Stmt::SetIndex(t, k, v, _) => vec![t, k, v],
+            Stmt::MultiAssign(_, es, _) | Stmt::Let(_, es, _) | Stmt::Return(es, _) => es.iter().collect(),
+            Stmt::Block(_) | Stmt::FunctionDef(_) => Vec::new(),
+        }
+    }
+
+    /// Where the statement is; a block has no span of its own.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Stmt::Assign(.., span) | Stmt::SetIndex(.., span) | Stmt::MultiAssign(.., span) | Stmt::Let(.., span)
+            | Stmt::Print(_, span) | Stmt::Expr(_, span) | Stmt::Return(_, span) => Some(*span),
+            Stmt::FunctionDef(f) => Some(f.span),
+            Stmt::Block(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
     pub name: String,
     pub params: Vec<String>,
+    /// Whether `...` ends the parameter list.
+    pub variadic: bool,
     pub body: Vec<Stmt>,
+    /// The name, or `fn` of an anonymous function.
+    pub span: Span,
 }
\ No newline at end of file
@@ -1,17 +1,110 @@
 /* ================= Bytecode ================= */
 
+use std::fmt;
+
+use crate::ast::CmpOp;
+
 #[derive(Debug, Clone)]
 pub enum BC {
     // values
     LoadConst(i64),
+    LoadFloat(f64),
+    LoadStr(String),
+    LoadBool(bool),
+    LoadNil,
     LoadVar(String),
     StoreVar(String),
+    Pop,
     Add,
+    Concat,
+    Compare(CmpOp),
+    Not,
+
+    // superinstructions the peephole pass fuses from the pairs in comments
+    AddConst(i64),                  // LoadConst n; Add
+    StoreLoad(String),              // StoreVar x; LoadVar x: stores the top and keeps it
+    LoadVarLoadVar(String, String), // LoadVar a; LoadVar b
+
+    // tables
+    NewTable(usize),  // pops n key/value pairs into a new table, then an open list as items n+1..
+    GetIndex,         // t k -> t[k]
+    SetIndex,         // t k v -> (t[k] = v)
 
-    // function/misc
+    // control flow, targets are absolute pcs
+    JumpIfFalseOrPop(usize), // `and`: keep a falsy top and jump, else pop it
+    JumpIfTrueOrPop(usize),  // `or`: keep a truthy top and jump, else pop it
+
+    // function/misc; a call pushes its first result. An open list, from
+    // Results(None) or Varargs(None), goes last into the call or RetN after
+    // it, on top of the values it counts.
+    Closure(String),     // closure over the named prototype, capturing its upvalues
     Call(String, usize), // func name, argc
+    CallValue(usize),    // f args.. -> f(args..), argc
+    TailCall(String, usize), // `return f(..)`: the callee takes over the caller's frame; before a Ret, returning one result
+    TailCallValue(usize),
+    Results(Option<usize>), // after a call: its first result -> n results, or all as an open list
+    Varargs(Option<usize>), // extra arguments of a variadic call: n of them, or all as an open list
     Ret,                 // return top of stack
+    RetN(usize),         // return the top n values
     Print,               // builtin
 }
 
+impl BC {
+    /// Where a jump goes.
+    pub fn target(&self) -> Option<usize> {
+        match self {
+            BC::JumpIfFalseOrPop(t) | BC::JumpIfTrueOrPop(t) => Some(*t),
+            _ => None,
+        }
+    }
+
+    pub fn target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            BC::JumpIfFalseOrPop(t) | BC::JumpIfTrueOrPop(t) => Some(t),
+            _ => None,
+        }
+    }
+}
 
+/// The listing form: the opcode, then its operands separated by spaces,
+/// strings quoted, `*` for an open list.
+impl fmt::Display for BC {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |n: &Option<usize>| n.map_or(String::from("*"), |n| n.to_string());
+        match self {
+            BC::LoadConst(n) => write!(f, "LoadConst {n}"),
+            BC::LoadFloat(x) => write!(f, "LoadFloat {x:?}"),
+            BC::LoadStr(s) => write!(f, "LoadStr {s:?}"),
+            BC::LoadBool(b) => write!(f, "LoadBool {b}"),
+            BC::LoadNil => write!(f, "LoadNil"),
+            BC::LoadVar(x) => write!(f, "LoadVar {x}"),
+            BC::StoreVar(x) => write!(f, "StoreVar {x}"),
+            BC::Pop => write!(f, "Pop"),
+            BC::Add => write!(f, "Add"),
+            BC::Concat => write!(f, "Concat"),
+            BC::Compare(op) => {
+                let op = match op { CmpOp::Eq => "==", CmpOp::Ne => "!=", CmpOp::Lt => "<", CmpOp::Le => "<=", CmpOp::Gt => ">", CmpOp::Ge => ">=" };
+                write!(f, "Compare {op}")
+            }
+            BC::Not => write!(f, "Not"),
+            BC::AddConst(n) => write!(f, "AddConst {n}"),
+            BC::StoreLoad(x) => write!(f, "StoreLoad {x}"),
+            BC::LoadVarLoadVar(a, b) => write!(f, "LoadVarLoadVar {a} {b}"),
+            BC::NewTable(n) => write!(f, "NewTable {n}"),
+            BC::GetIndex => write!(f, "GetIndex"),
+            BC::SetIndex => write!(f, "SetIndex"),
+            BC::JumpIfFalseOrPop(t) => write!(f, "JumpIfFalseOrPop {t:04}"),
+            BC::JumpIfTrueOrPop(t) => write!(f, "JumpIfTrueOrPop {t:04}"),
+            BC::Closure(name) => write!(f, "Closure {name}"),
+            BC::Call(name, argc) => write!(f, "Call {name} {argc}"),
+            BC::CallValue(argc) => write!(f, "CallValue {argc}"),
+            BC::TailCall(name, argc) => write!(f, "TailCall {name} {argc}"),
+            BC::TailCallValue(argc) => write!(f, "TailCallValue {argc}"),
+            BC::Results(n) => write!(f, "Results {}", count(n)),
+            BC::Varargs(n) => write!(f, "Varargs {}", count(n)),
+            BC::Ret => write!(f, "Ret"),
+            BC::RetN(n) => write!(f, "RetN {n}"),
+            BC::Print => write!(f, "Print"),
+        }
+    }
+}
@@ -0,0 +1,166 @@
+/* ================= Static checks ================= */
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::{Expr, Function, Stmt};
+use crate::lexer::Span;
+use crate::native::Natives;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity { Error, Warning }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub msg: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity { Severity::Error => "error", Severity::Warning => "warning" };
+        write!(f, "line {}: {severity}: {}", self.span.line, self.msg)
+    }
+}
+
+/// Checks a program before it runs. Errors are calls by name to functions
+/// defined nowhere, calls with the wrong number of arguments, functions
+/// defined twice and repeated parameters; statements after a `return` are
+/// warned about.
+///
+/// A name the program ever assigns, declares or takes as a parameter may
+/// hold any function, so calls through it are not checked, and neither are
+/// calls to the host's globals, the names `is_global` knows.
+pub fn check(program: &[Stmt], natives: &Natives, is_global: &dyn Fn(&str) -> bool) -> Vec<Diagnostic> {
+    let mut c = Checker { natives, is_global, funs: HashMap::new(), vars: HashSet::new(), diags: Vec::new() };
+    for s in program {
+        if let Stmt::FunctionDef(f) = s {
+            match c.funs.get(f.name.as_str()) {
+                Some(first) => {
+                    let msg = format!("function `{}` is already defined on line {}", f.name, first.span.line);
+                    c.error(f.span, msg);
+                }
+                None => { c.funs.insert(&f.name, f); }
+            }
+        }
+    }
+    declared(program, &mut c.vars, true);
+    c.body(program);
+    c.diags
+}
+
+struct Checker<'a> {
+    natives: &'a Natives,
+    is_global: &'a dyn Fn(&str) -> bool,
+    funs: HashMap<&'a str, &'a Function>,
+    vars: HashSet<&'a str>,
+    diags: Vec<Diagnostic>,
+}
+
+/// Every name `body` assigns, declares or takes as a parameter, in nested
+/// functions too. Functions not defined at the `top` level are locals.
+pub(crate) fn declared<'a>(body: &'a [Stmt], vars: &mut HashSet<&'a str>, top: bool) {
+    fn in_expr<'a>(e: &'a Expr, vars: &mut HashSet<&'a str>) {
+        if let Expr::Function(f) = e { in_fn(f, vars); }
+        for o in e.operands() { in_expr(o, vars); }
+    }
+    fn in_fn<'a>(f: &'a Function, vars: &mut HashSet<&'a str>) {
+        vars.extend(f.params.iter().map(String::as_str));
+        declared(&f.body, vars, false);
+    }
+    for s in body {
+        match s {
+            Stmt::Assign(name, ..) => { vars.insert(name); }
+            Stmt::MultiAssign(names, ..) | Stmt::Let(names, ..) => vars.extend(names.iter().map(String::as_str)),
+            Stmt::Block(b) => declared(b, vars, false),
+            Stmt::FunctionDef(f) => {
+                if !top { vars.insert(&f.name); }
+                in_fn(f, vars);
+            }
+            _ => {}
+        }
+        for e in s.exprs() { in_expr(e, vars); }
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, span: Span, msg: String) {
+        self.diags.push(Diagnostic { severity: Severity::Error, span, msg });
+    }
+
+    /// Checks the statements of a body, returning the `return` it always ends in, if any.
+    fn body(&mut self, body: &'a [Stmt]) -> Option<Span> {
+        let mut returned = None;
+        let mut warned = false;
+        for s in body {
+            // once per body, for the first statement that cannot run
+            if let Some(ret) = returned && !warned {
+                let span = first_span(s).unwrap_or(ret);
+                self.diags.push(Diagnostic { severity: Severity::Warning, span, msg: String::from("unreachable code after `return`") });
+                warned = true;
+            }
+            let ends = match s {
+                Stmt::Return(_, span) => Some(*span),
+                Stmt::Block(b) => self.body(b),
+                Stmt::FunctionDef(f) => { self.function(f); None }
+                _ => None,
+            };
+            returned = returned.or(ends);
+            for e in s.exprs() { self.expr(e); }
+        }
+        returned
+    }
+
+    fn function(&mut self, f: &'a Function) {
+        let mut seen = HashSet::new();
+        for p in &f.params {
+            if !seen.insert(p) {
+                let of = if f.name.is_empty() { String::from("an anonymous function") } else { format!("`{}`", f.name) };
+                self.error(f.span, format!("duplicate parameter `{p}` of {of}"));
+            }
+        }
+        self.body(&f.body);
+    }
+
+    fn expr(&mut self, e: &'a Expr) {
+        match e {
+            Expr::Function(f) => self.function(f),
+            Expr::Call(callee, args, span) => if let Expr::Var(name) = &**callee { self.call(name, args, *span) },
+            _ => {}
+        }
+        for o in e.operands() { self.expr(o); }
+    }
+
+    /// A call by name resolves like in the VM: a variable, else a script
+    /// function, else a native.
+    fn call(&mut self, name: &str, args: &[Expr], span: Span) {
+        if self.vars.contains(name) { return; }
+        let (params, variadic) = match (self.funs.get(name), self.natives.get(name)) {
+            (Some(f), _) => (f.params.len(), f.variadic),
+            (None, Some(native)) => match native.arity {
+                Some(n) => (n, false),
+                None => return,
+            },
+            (None, None) if (self.is_global)(name) => return,
+            (None, None) => return self.error(span, format!("undefined function `{name}`")),
+        };
+        // a call or `...` last passes on any number of values
+        let spread = matches!(args.last(), Some(Expr::Call(..) | Expr::Vararg));
+        let got = args.len() - spread as usize;
+        let ok = if spread { variadic || got <= params } else { got == params || (variadic && got > params) };
+        if !ok {
+            let expected = if variadic { format!("at least {params}") } else { params.to_string() };
+            let got = if spread { format!("{got} or more") } else { got.to_string() };
+            self.error(span, format!("arity mismatch for {name}: expected {expected} arguments, got {got}"));
+        }
+    }
+}
+
+/// Where a statement starts: a block's is its first statement's.
+fn first_span(s: &Stmt) -> Option<Span> {
+    match s {
+        Stmt::Block(b) => b.iter().find_map(first_span),
+        s => s.span(),
+    }
+}
@@ -1,5 +1,6 @@
 /* ================= Codegen ================= */
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 use crate::ast::*;
 use crate::bytecode::*;
@@ -9,68 +10,438 @@ use crate::bytecode::*;
 pub struct FunctionProto {
     pub name: String,
     pub params: Vec<String>,
+    /// Whether calls may pass more arguments than `params`, for `...`.
+    pub variadic: bool,
+    /// Locals of enclosing functions this one captures, in closure order.
+    pub upvals: Vec<String>,
     pub code: Vec<BC>,
+    /// Source line of each instruction of `code`.
+    pub lines: Vec<usize>,
+    /// Line the definition starts on; 0 for main.
+    pub line: usize,
+    /// Prototypes of the functions defined in this one, in source order.
+    pub children: Vec<Rc<FunctionProto>>,
 }
 
 
 #[derive(Debug, Clone)]
 pub struct Module {
-    pub funs: HashMap<String, FunctionProto>,
+    /// Every prototype, anonymous and nested functions included under
+    /// generated names no call by name can reach.
+    pub funs: HashMap<String, Rc<FunctionProto>>,
     pub main: FunctionProto,
 }
 
+/// A function being compiled, for resolving the names it uses.
+struct Scope {
+    name: String,
+    params: HashSet<String>,
+    /// Parameters and the names the body assigns without a `let`.
+    locals: HashSet<String>,
+    upvals: Vec<String>,
+    /// `let` variables and their slots, one list per open block, innermost
+    /// last. main starts without one: `let` at its top level declares a global.
+    blocks: Vec<Vec<(String, usize)>>,
+    /// Slots of blocks that ended, for the next `let` to reuse.
+    free: Vec<usize>,
+    /// Slots handed out so far. A nested function numbers its own from
+    /// here, so none of them is named like a slot it captures.
+    slots: usize,
+    /// Slots a closure captured; their cells stay with the frame, so they
+    /// are never reused.
+    captured: HashSet<usize>,
+    children: Vec<Rc<FunctionProto>>,
+    /// Source line of each instruction compiled so far.
+    lines: Vec<usize>,
+}
+
+impl Scope {
+    fn new(name: String, params: HashSet<String>, locals: HashSet<String>, slots: usize) -> Self {
+        Scope { name, params, locals, upvals: Vec::new(), blocks: Vec::new(), free: Vec::new(), slots, captured: HashSet::new(), children: Vec::new(), lines: Vec::new() }
+    }
+
+    /// Slot of the innermost `let` of `name` in scope.
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.blocks.iter().rev().flat_map(|b| b.iter().rev()).find(|(n, _)| n == name).map(|&(_, slot)| slot)
+    }
+}
+
+/// Variable a slot is stored in; `$` keeps it apart from every name.
+fn slot_var(slot: usize) -> String { format!("${slot}") }
+
+/// Names `body` assigns with no `let` of them in scope.
+fn assigned<'a>(body: &'a [Stmt], lets: &mut Vec<&'a str>, out: &mut HashSet<String>) {
+    let mark = lets.len();
+    for s in body {
+        match s {
+            Stmt::Assign(name, ..) if !lets.contains(&name.as_str()) => { out.insert(name.clone()); }
+            Stmt::MultiAssign(names, ..) => out.extend(names.iter().filter(|n| !lets.contains(&n.as_str())).cloned()),
+            Stmt::Let(names, ..) => lets.extend(names.iter().map(String::as_str)),
+            Stmt::FunctionDef(f) => lets.push(&f.name),
+            Stmt::Block(body) => assigned(body, lets, out),
+            _ => {}
+        }
+    }
+    lets.truncate(mark);
+}
+
+#[derive(Default)]
+struct Compiler {
+    funs: HashMap<String, Rc<FunctionProto>>,
+    // main, then the functions enclosing the code being compiled, innermost
+    // last; main's variables outside blocks are globals
+    scopes: Vec<Scope>,
+    anon: usize,
+}
+
+impl Compiler {
+    fn scope(&mut self) -> &mut Scope { self.scopes.last_mut().unwrap() }
 
-pub fn gen_expr(code: &mut Vec<BC>, e: &Expr) {
-    match e {
-        Expr::Number(n) => code.push(BC::LoadConst(*n)),
-        Expr::Var(v) => code.push(BC::LoadVar(v.clone())),
-        Expr::Add(a, b) => {
-            gen_expr(code,a); 
-            gen_expr(code,b); 
-            code.push(BC::Add); 
+    /// The variable `name` refers to here: a `let` slot, a parameter, a
+    /// variable of an enclosing function, recorded as an upvalue of every
+    /// function in between, else the name itself, a local or a global.
+    fn resolve(&mut self, name: &str) -> String {
+        let (cur, outer) = self.scopes.split_last_mut().unwrap();
+        if let Some(slot) = cur.lookup(name) { return slot_var(slot); }
+        if cur.params.contains(name) { return name.to_string(); }
+        let found = outer.iter_mut().enumerate().rev().find_map(|(j, s)| match s.lookup(name) {
+            Some(slot) => { s.captured.insert(slot); Some((j, slot_var(slot))) }
+            None => s.locals.contains(name).then(|| (j, name.to_string())),
+        });
+        let Some((j, var)) = found else { return name.to_string() };
+        for s in &mut self.scopes[j + 1..] {
+            if !s.upvals.contains(&var) { s.upvals.push(var.clone()); }
         }
-        Expr::Call(name, args) => {
-            for a in args {
-                gen_expr(code, a);
+        var
+    }
+
+    /// Variable for a `let` of `name` in the innermost block.
+    fn declare(&mut self, name: &str) -> String {
+        let scope = self.scope();
+        let Some(block) = scope.blocks.last_mut() else { return name.to_string() };
+        let slot = scope.free.pop().unwrap_or_else(|| { scope.slots += 1; scope.slots - 1 });
+        block.push((name.to_string(), slot));
+        slot_var(slot)
+    }
+
+    fn gen_block(&mut self, code: &mut Vec<BC>, body: &[Stmt]) {
+        self.scope().blocks.push(Vec::new());
+        for st in body { self.gen_stmt(code, st); }
+        let scope = self.scope();
+        let block = scope.blocks.pop().unwrap();
+        scope.free.extend(block.into_iter().map(|(_, slot)| slot).filter(|s| !scope.captured.contains(s)));
+    }
+
+    fn compile_fn(&mut self, f: &Function, name: String) -> Rc<FunctionProto> {
+        let params: HashSet<String> = f.params.iter().cloned().collect();
+        let mut locals = params.clone();
+        assigned(&f.body, &mut Vec::new(), &mut locals);
+        let slots = self.scope().slots;
+        self.scopes.push(Scope::new(name.clone(), params, locals, slots));
+        let mut code = Vec::new();
+        self.gen_block(&mut code, &f.body);
+        // ensure implicit return (like Lua) if none present
+        if !matches!(code.last(), Some(BC::Ret | BC::RetN(_))) { code.push(BC::LoadNil); code.push(BC::Ret); }
+        let scope = self.scopes.pop().unwrap();
+        let lines = end_lines(scope.lines, f.span.line, code.len());
+        let (code, lines) = peephole(code, lines);
+        let proto = Rc::new(FunctionProto {
+            name: name.clone(), params: f.params.clone(), variadic: f.variadic, upvals: scope.upvals, code, lines, line: f.span.line,
+            children: scope.children,
+        });
+        self.funs.insert(name, Rc::clone(&proto));
+        self.scope().children.push(Rc::clone(&proto));
+        proto
+    }
+
+    /// Name for the prototype of a function defined in the current one:
+    /// `parent#n` when anonymous, `parent.name` when nested, made unique
+    /// with `#n` if a sibling took it already.
+    fn child_name(&mut self, name: &str) -> String {
+        let parent = &self.scopes.last().unwrap().name;
+        let nested = format!("{parent}.{name}");
+        if !name.is_empty() && !self.funs.contains_key(&nested) { return nested; }
+        let name = format!("{}#{}", if name.is_empty() { parent } else { &nested }, self.anon);
+        self.anon += 1;
+        name
+    }
+
+    fn gen_expr(&mut self, code: &mut Vec<BC>, e: &Expr) {
+        match e {
+            Expr::Number(n) => code.push(BC::LoadConst(*n)),
+            Expr::Float(x) => code.push(BC::LoadFloat(*x)),
+            Expr::Str(s) => code.push(BC::LoadStr(s.clone())),
+            Expr::Bool(b) => code.push(BC::LoadBool(*b)),
+            Expr::Nil => code.push(BC::LoadNil),
+            Expr::Var(v) => {
+                let var = self.resolve(v);
+                code.push(BC::LoadVar(var));
+            }
+            Expr::Add(a, b) => {
+                self.gen_expr(code, a);
+                self.gen_expr(code, b);
+                code.push(BC::Add);
+            }
+            Expr::Concat(a, b) => {
+                self.gen_expr(code, a);
+                self.gen_expr(code, b);
+                code.push(BC::Concat);
+            }
+            Expr::Compare(op, a, b) => {
+                self.gen_expr(code, a);
+                self.gen_expr(code, b);
+                code.push(BC::Compare(*op));
+            }
+            Expr::Table(array, fields) if fields.is_empty() && matches!(array.last(), Some(Expr::Call(..) | Expr::Vararg)) => {
+                // `{ a, f() }` takes all of f's results, but only last in a
+                // table without keyed entries, whose order the AST loses
+                let (last, rest) = array.split_last().unwrap();
+                for (i, v) in rest.iter().enumerate() {
+                    code.push(BC::LoadConst(i as i64 + 1));
+                    self.gen_expr(code, v);
+                }
+                self.gen_exprs(code, std::slice::from_ref(last));
+                code.push(BC::NewTable(rest.len()));
+            }
+            Expr::Table(array, fields) => {
+                for (i, v) in array.iter().enumerate() {
+                    code.push(BC::LoadConst(i as i64 + 1));
+                    self.gen_expr(code, v);
+                }
+                for (k, v) in fields {
+                    self.gen_expr(code, k);
+                    self.gen_expr(code, v);
+                }
+                code.push(BC::NewTable(array.len() + fields.len()));
+            }
+            Expr::Index(t, k) => {
+                self.gen_expr(code, t);
+                self.gen_expr(code, k);
+                code.push(BC::GetIndex);
+            }
+            Expr::And(a, b) | Expr::Or(a, b) => {
+                self.gen_expr(code, a);
+                let jump = code.len();
+                code.push(BC::LoadNil); // patched below once the target is known
+                self.gen_expr(code, b);
+                let end = code.len();
+                code[jump] = if matches!(e, Expr::And(..)) { BC::JumpIfFalseOrPop(end) } else { BC::JumpIfTrueOrPop(end) };
+            }
+            Expr::Not(a) => {
+                self.gen_expr(code, a);
+                code.push(BC::Not);
+            }
+            // a call and `...` push one value here already
+            Expr::Paren(a) => self.gen_expr(code, a),
+            Expr::Function(f) => {
+                let name = self.child_name("");
+                self.compile_fn(f, name.clone());
+                code.push(BC::Closure(name));
+            }
+            Expr::Call(callee, args, _) => {
+                if let Expr::Var(name) = &**callee {
+                    let var = self.resolve(name);
+                    let argc = self.gen_exprs(code, args);
+                    code.push(BC::Call(var, argc));
+                } else {
+                    self.gen_expr(code, callee);
+                    let argc = self.gen_exprs(code, args);
+                    code.push(BC::CallValue(argc));
+                }
+            }
+            Expr::Vararg => code.push(BC::Varargs(Some(1))),
+        }
+    }
+
+    /// Evaluates a list of expressions where a call or `...` last spreads
+    /// into an open list. Returns how many values the list has besides it.
+    fn gen_exprs(&mut self, code: &mut Vec<BC>, es: &[Expr]) -> usize {
+        let Some((last, rest)) = es.split_last() else { return 0 };
+        for e in rest { self.gen_expr(code, e); }
+        match last {
+            Expr::Call(..) => { self.gen_expr(code, last); code.push(BC::Results(None)); rest.len() }
+            Expr::Vararg => { code.push(BC::Varargs(None)); rest.len() }
+            _ => { self.gen_expr(code, last); es.len() }
+        }
+    }
+
+    /// Turns the call at `at`, if that is one, into a tail call. Code after
+    /// it only runs when the callee turns out to be a native or a trace.
+    fn tail_call(&self, code: &mut [BC], at: usize) {
+        if self.scopes.len() == 1 { return; }
+        let op = &mut code[at];
+        match op {
+            BC::Call(name, argc) => *op = BC::TailCall(std::mem::take(name), *argc),
+            BC::CallValue(argc) => *op = BC::TailCallValue(*argc),
+            _ => {}
+        }
+    }
+
+    /// Exactly `n` values from `values` for as many targets: the last one fills
+    /// the targets left, nils pad, and values past the last target are dropped.
+    fn gen_values(&mut self, code: &mut Vec<BC>, n: usize, values: &[Expr]) {
+        for (i, e) in values.iter().enumerate() {
+            if i >= n {
+                self.gen_expr(code, e);
+                code.push(BC::Pop);
+                continue;
+            }
+            let want = if i + 1 == values.len() { n - i } else { 1 };
+            match e {
+                Expr::Call(..) if want != 1 => { self.gen_expr(code, e); code.push(BC::Results(Some(want))); }
+                Expr::Vararg => code.push(BC::Varargs(Some(want))),
+                _ => {
+                    self.gen_expr(code, e);
+                    code.extend(std::iter::repeat_n(BC::LoadNil, want - 1));
+                }
+            }
+        }
+        // `let x;`
+        if values.is_empty() { code.extend(std::iter::repeat_n(BC::LoadNil, n)); }
+    }
+
+    fn gen_stmt(&mut self, code: &mut Vec<BC>, s: &Stmt) {
+        self.gen_stmt_code(code, s);
+        // the statements of a block gave their code lines already
+        if let Some(span) = s.span() { self.scope().lines.resize(code.len(), span.line); }
+    }
+
+    fn gen_stmt_code(&mut self, code: &mut Vec<BC>, s: &Stmt) {
+        match s {
+            Stmt::Assign(name, e, _) => { self.gen_expr(code, e); let var = self.resolve(name); code.push(BC::StoreVar(var)); }
+            Stmt::SetIndex(t, k, v, _) => { self.gen_expr(code, t); self.gen_expr(code, k); self.gen_expr(code, v); code.push(BC::SetIndex); }
+            Stmt::Print(e, _)     => { self.gen_expr(code, e); code.push(BC::Print); }
+            Stmt::Expr(e, _)      => { self.gen_expr(code, e); code.push(BC::Pop); }
+            Stmt::MultiAssign(names, values, _) => {
+                self.gen_values(code, names.len(), values);
+                for name in names.iter().rev() {
+                    let var = self.resolve(name);
+                    code.push(BC::StoreVar(var));
+                }
+            }
+            Stmt::Let(names, values, _) => {
+                // the values still see what the names shadow
+                self.gen_values(code, names.len(), values);
+                let vars: Vec<String> = names.iter().map(|n| self.declare(n)).collect();
+                for var in vars.into_iter().rev() { code.push(BC::StoreVar(var)); }
+            }
+            Stmt::Block(body) => self.gen_block(code, body),
+            Stmt::Return(values, _) if values.len() == 1 && !matches!(values[0], Expr::Call(..) | Expr::Vararg) => {
+                self.gen_expr(code, &values[0]);
+                // a call right before the Ret is in tail position at the end of
+                // `and`/`or`, whose jumps land on the Ret, or in parentheses;
+                // the Ret tells the VM to pass on only its first result. main
+                // has no frame to reuse.
+                let at = code.len() - 1;
+                self.tail_call(code, at);
+                code.push(BC::Ret);
+            }
+            Stmt::Return(values, _) => {
+                let n = self.gen_exprs(code, values);
+                // `return f(..)`: the call is followed by Results(None)
+                if matches!(values.last(), Some(Expr::Call(..))) {
+                    let at = code.len() - 2;
+                    self.tail_call(code, at);
+                }
+                code.push(BC::RetN(n));
+            }
+            // the top level of main binds its functions before anything runs,
+            // see compile_module; any other binds like a `let`, in scope in
+            // its own body so that it can call itself
+            Stmt::FunctionDef(f) => {
+                let var = self.declare(&f.name);
+                let name = self.child_name(&f.name);
+                self.compile_fn(f, name.clone());
+                code.push(BC::Closure(name));
+                code.push(BC::StoreVar(var));
             }
-            code.push(BC::Call(name.clone(), args.len()));
         }
     }
 }
 
-pub fn gen_stmt(code: &mut Vec<BC>, s: &Stmt) {
-    match s {
-        Stmt::Assign(name, e) => { gen_expr(code, e); code.push(BC::StoreVar(name.clone())); }
-        Stmt::Print(e)        => { gen_expr(code, e); code.push(BC::Print); }
-        Stmt::Return(e)       => { gen_expr(code, e); code.push(BC::Ret); }
-        Stmt::FunctionDef(_)  => { /* handled at module level */ }
+/// Lines of `len` instructions, given those of all but the implicit return
+/// at the end, which goes on the last line given, or `line` for none.
+fn end_lines(mut lines: Vec<usize>, line: usize, len: usize) -> Vec<usize> {
+    let last = lines.last().copied().unwrap_or(line);
+    lines.resize(len, last);
+    lines
+}
+
+/// Rewrites the code of a function into shorter code that does the same:
+/// a jump landing on a jump of its kind goes on to that one's target, code
+/// after a return that no jump lands in goes, and common pairs of
+/// instructions become the superinstructions they make up. No pair a jump
+/// lands in the middle of is fused. `lines` are those of the instructions,
+/// a superinstruction taking the line of the first of its pair.
+pub fn peephole(mut code: Vec<BC>, lines: Vec<usize>) -> (Vec<BC>, Vec<usize>) {
+    // targets only lie ahead, so threading ends
+    for pc in 0..code.len() {
+        let Some(mut target) = code[pc].target() else { continue };
+        let kind = std::mem::discriminant(&code[pc]);
+        while let Some(next) = code.get(target).filter(|op| std::mem::discriminant(*op) == kind).and_then(BC::target) {
+            target = next;
+        }
+        *code[pc].target_mut().unwrap() = target;
     }
+    let targets: HashSet<usize> = code.iter().filter_map(BC::target).collect();
+    // the new pc of each old one, and of the end
+    let mut map = vec![0; code.len() + 1];
+    let mut out = Vec::with_capacity(code.len());
+    let mut out_lines = Vec::with_capacity(code.len());
+    let mut dead = false;
+    let mut ops = code.into_iter().enumerate().peekable();
+    while let Some((pc, op)) = ops.next() {
+        map[pc] = out.len();
+        dead &= !targets.contains(&pc);
+        if dead { continue; }
+        dead = matches!(op, BC::Ret | BC::RetN(_));
+        let next = ops.peek().filter(|(next, _)| !targets.contains(next)).map(|(_, op)| op);
+        out_lines.push(lines[pc]);
+        let fused = match (&op, next) {
+            (BC::LoadConst(n), Some(BC::Add)) => BC::AddConst(*n),
+            (BC::StoreVar(x), Some(BC::LoadVar(y))) if x == y => BC::StoreLoad(y.clone()),
+            (BC::LoadVar(a), Some(BC::LoadVar(b))) => BC::LoadVarLoadVar(a.clone(), b.clone()),
+            _ => { out.push(op); continue; }
+        };
+        let (next, _) = ops.next().unwrap();
+        map[next] = out.len();
+        out.push(fused);
+    }
+    *map.last_mut().unwrap() = out.len();
+    for op in &mut out {
+        if let Some(t) = op.target_mut() { *t = map[*t]; }
+    }
+    (out, out_lines)
 }
 
 pub fn compile_module(stmts: Vec<Stmt>) -> Module {
-    let mut funs = HashMap::new();
+    let mut c = Compiler::default();
+    c.scopes.push(Scope::new("main".into(), HashSet::new(), HashSet::new(), 0));
     let mut main_code = Vec::new();
 
-    // First, extract function defs into prototypes.
+    // First, extract function defs into prototypes; main binds each to a
+    // global of its name before anything else runs.
     for s in &stmts {
         if let Stmt::FunctionDef(f) = s {
-            let mut code = Vec::new();
-            for st in &f.body { gen_stmt(&mut code, st); }
-            // ensure implicit return (like Lua) if none present
-            if !matches!(code.last(), Some(BC::Ret)) { code.push(BC::LoadConst(0)); code.push(BC::Ret); }
-            funs.insert(f.name.clone(), FunctionProto { name: f.name.clone(), params: f.params.clone(), code });
+            c.compile_fn(f, f.name.clone());
+            main_code.push(BC::Closure(f.name.clone()));
+            main_code.push(BC::StoreVar(f.name.clone()));
+            c.scope().lines.resize(main_code.len(), f.span.line);
         }
     }
 
     // Now compile top-level into main()
     for s in &stmts {
         if !matches!(s, Stmt::FunctionDef(_)) {
-            gen_stmt(&mut main_code, s);
+            c.gen_stmt(&mut main_code, s);
         }
     }
     // main returns 0
-    if !matches!(main_code.last(), Some(BC::Ret)) { main_code.push(BC::LoadConst(0)); main_code.push(BC::Ret); }
+    if !matches!(main_code.last(), Some(BC::Ret | BC::RetN(_))) { main_code.push(BC::LoadConst(0)); main_code.push(BC::Ret); }
 
-    let main = FunctionProto { name: "main".into(), params: vec![], code: main_code };
-    Module { funs, main }
-}
\ No newline at end of file
+    let scope = c.scopes.pop().unwrap();
+    let lines = end_lines(scope.lines, 1, main_code.len());
+    let (code, lines) = peephole(main_code, lines);
+    let main = FunctionProto { name: "main".into(), params: vec![], variadic: false, upvals: vec![], code, lines, line: 0, children: scope.children };
+    Module { funs: c.funs, main }
+}
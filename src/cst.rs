@@ -0,0 +1,109 @@
+/* ================= Concrete syntax tree ================= */
+
+use std::fmt;
+
+use crate::lexer::Token;
+
+/// What a node of the tree is. Literals and names are plain leaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Program,
+    // statements
+    FnDef, Print, Return, Let, Assign, ExprStmt, Block,
+    // expressions
+    Binary, Not, Paren, Table, Field, Index, Member, Call, FnExpr,
+    /// `(a, b)` after a callee.
+    Args,
+    /// `(a, b, ...)` of a function.
+    Params,
+}
+
+/// A token with the whitespace and comments before it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leaf {
+    pub token: Token,
+    pub trivia: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    Node(Node),
+    Leaf(Leaf),
+}
+
+/// A node of the lossless tree: every byte of the source is in exactly one
+/// leaf, as its text or trivia, and the Program root ends with the EOF leaf
+/// holding whatever trails the last token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub kind: Kind,
+    pub children: Vec<Element>,
+}
+
+impl Node {
+    /// The first leaf under this node.
+    pub fn first_leaf(&self) -> Option<&Leaf> {
+        self.children.iter().find_map(|c| match c {
+            Element::Node(n) => n.first_leaf(),
+            Element::Leaf(l) => Some(l),
+        })
+    }
+}
+
+/// Prints the source the tree was parsed from, byte for byte.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in &self.children {
+            match c {
+                Element::Node(n) => write!(f, "{n}")?,
+                Element::Leaf(l) => write!(f, "{}{}", l.trivia, l.text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a tree bottom-up as the parser goes: nodes are started before
+/// their first token and finished after their last. A node whose first child
+/// was parsed before it was known, like the left operand of a binary
+/// operator, starts at a checkpoint taken before that child.
+#[derive(Default)]
+pub struct Builder {
+    stack: Vec<Node>,
+}
+
+impl Builder {
+    pub fn start(&mut self, kind: Kind) {
+        self.stack.push(Node { kind, children: Vec::new() });
+    }
+
+    /// Position in the open node for `start_at`.
+    pub fn checkpoint(&self) -> usize {
+        self.stack.last().map_or(0, |n| n.children.len())
+    }
+
+    /// Starts a node taking in the children added since `checkpoint`.
+    pub fn start_at(&mut self, checkpoint: usize, kind: Kind) {
+        let children = self.stack.last_mut().unwrap().children.split_off(checkpoint);
+        self.stack.push(Node { kind, children });
+    }
+
+    pub fn finish(&mut self) {
+        let node = self.stack.pop().unwrap();
+        match self.stack.last_mut() {
+            Some(parent) => parent.children.push(Element::Node(node)),
+            // the root stays until `root` takes it
+            None => self.stack.push(node),
+        }
+    }
+
+    pub fn push(&mut self, leaf: Leaf) {
+        self.stack.last_mut().unwrap().children.push(Element::Leaf(leaf));
+    }
+
+    /// The finished root node.
+    pub fn root(mut self) -> Option<Node> {
+        if self.stack.len() == 1 { self.stack.pop() } else { None }
+    }
+}
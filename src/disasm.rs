@@ -0,0 +1,116 @@
+/* ================= Disassembler ================= */
+
+use std::fmt::Write;
+
+use crate::bytecode::BC;
+use crate::codegen::{FunctionProto, Module};
+
+/// Lists the code of `module`: main, then every function in the order it
+/// is defined in the source, nested ones after their parent's code. Each
+/// instruction is on a line of its own as
+///
+/// ```text
+/// 0003    2  Add
+/// ```
+///
+/// with its pc, the depth of the operand stack before it runs and the
+/// instruction. An open list of values on top, whose length only the run
+/// knows, shows as `+?` after the depth, and a depth that differs by the
+/// path taken to the instruction as `?`. Before the
+/// first instruction of each source line comes `; N:` and, given the
+/// source `src`, the text of line N. The format does not change with
+/// anything but the code, so that listings can be compared as they are.
+pub fn disassemble(module: &Module, src: Option<&str>) -> String {
+    let lines: Vec<&str> = src.map_or(Vec::new(), |s| s.lines().collect());
+    let mut out = String::new();
+    function(&mut out, &module.main, &lines);
+    out
+}
+
+fn function(out: &mut String, f: &FunctionProto, src: &[&str]) {
+    if !out.is_empty() { out.push('\n'); }
+    if f.name == "main" {
+        out.push_str("main\n");
+    } else {
+        let dots = if f.variadic { if f.params.is_empty() { "..." } else { ", ..." } } else { "" };
+        let _ = writeln!(out, "fn {}({}{dots}) ; line {}", f.name, f.params.join(", "), f.line);
+    }
+    if !f.upvals.is_empty() { let _ = writeln!(out, "; upvalues: {}", f.upvals.join(", ")); }
+    let mut line = None;
+    for (pc, (op, depth)) in f.code.iter().zip(depths(&f.code)).enumerate() {
+        let at = f.lines.get(pc).copied();
+        if at != line && let Some(n) = at {
+            let text = src.get(n.wrapping_sub(1)).map_or("", |s| s.trim());
+            let _ = writeln!(out, "{}", format!("; {n}: {text}").trim_end());
+        }
+        line = at;
+        let depth = match depth {
+            Depth::Unreached => String::from("-"),
+            Depth::Known(d, open) => format!("{d}{}", if open { "+?" } else { "" }),
+            Depth::Unknown => String::from("?"),
+        };
+        let _ = writeln!(out, "{pc:04} {depth:>4}  {op}");
+    }
+    let mut children: Vec<_> = f.children.iter().collect();
+    children.sort_by_key(|c| c.line);
+    for c in children { function(out, c, src); }
+}
+
+/// Depth of the operand stack before an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    /// Nothing reaches the instruction.
+    Unreached,
+    /// The values on the stack, and whether an open list is on top besides.
+    Known(usize, bool),
+    /// Paths to the instruction leave different depths.
+    Unknown,
+}
+
+impl Depth {
+    /// The depth where a path leaving `self` joins one leaving `other`.
+    fn join(self, other: Depth) -> Depth {
+        match (self, other) {
+            (Depth::Unreached, d) | (d, Depth::Unreached) => d,
+            (a, b) if a == b => a,
+            _ => Depth::Unknown,
+        }
+    }
+}
+
+/// Depth of the operand stack before each instruction. Code is laid out so
+/// that every jump goes forward, so one pass over it sees every path into
+/// an instruction before the instruction itself.
+fn depths(code: &[BC]) -> Vec<Depth> {
+    let mut at = vec![Depth::Unreached; code.len() + 1];
+    at[0] = Depth::Known(0, false);
+    for (pc, op) in code.iter().enumerate() {
+        if let Some(t) = op.target() { at[t] = at[t].join(at[pc]); }
+        let Depth::Known(d, open) = at[pc] else {
+            // what an unknown depth flows into is unknown too
+            if at[pc] == Depth::Unknown && !matches!(op, BC::Ret | BC::RetN(_)) { at[pc + 1] = Depth::Unknown; }
+            continue;
+        };
+        // values popped and pushed, and whether an open list is left on top
+        let (pops, pushes, open) = match op {
+            BC::LoadConst(_) | BC::LoadFloat(_) | BC::LoadStr(_) | BC::LoadBool(_) | BC::LoadNil | BC::LoadVar(_)
+            | BC::Closure(_) => (0, 1, open),
+            BC::LoadVarLoadVar(..) => (0, 2, open),
+            BC::StoreVar(_) | BC::Pop | BC::Print => (1, 0, open),
+            BC::Add | BC::Concat | BC::Compare(_) | BC::GetIndex => (2, 1, open),
+            BC::Not | BC::AddConst(_) | BC::StoreLoad(_) => (1, 1, open),
+            BC::SetIndex => (3, 0, open),
+            BC::NewTable(n) => (2 * n, 1, false),
+            BC::JumpIfFalseOrPop(_) | BC::JumpIfTrueOrPop(_) => (1, 0, open),
+            BC::Call(_, argc) | BC::TailCall(_, argc) => (*argc, 1, false),
+            BC::CallValue(argc) | BC::TailCallValue(argc) => (argc + 1, 1, false),
+            BC::Results(n) => (1, n.unwrap_or(0), n.is_none()),
+            BC::Varargs(n) => (0, n.unwrap_or(0), n.is_none()),
+            BC::Ret | BC::RetN(_) => continue,
+        };
+        let next = d.checked_sub(pops).map_or(Depth::Unknown, |d| Depth::Known(d + pushes, open));
+        at[pc + 1] = at[pc + 1].join(next);
+    }
+    at.truncate(code.len());
+    at
+}
@@ -0,0 +1,172 @@
+/* ================= Embedding API ================= */
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::Stmt;
+use crate::checker::{self, Diagnostic, Severity};
+use crate::codegen::{self, Module};
+use crate::gc::GcStats;
+use crate::jit::JitOptions;
+use crate::lexer::Lexer;
+use crate::native::NativeFn;
+use crate::optimizer;
+use crate::parser::{ParseError, Parser};
+use crate::vm::{RuntimeError, Value, VM};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Parse(ParseError),
+    /// The errors the static checks found; see [`checker::check`].
+    Check(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "parse error: {e}"),
+            Error::Check(diags) => {
+                write!(f, "check failed")?;
+                diags.iter().try_for_each(|d| write!(f, "\n  {d}"))
+            }
+            Error::Runtime(e) => write!(f, "runtime error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self { Error::Parse(e) }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(e: RuntimeError) -> Self { Error::Runtime(e) }
+}
+
+/// Owns a loaded module together with the VM state (globals, traces) it runs in.
+pub struct Engine {
+    vm: VM,
+    optimize: bool,
+}
+
+impl Default for Engine {
+    fn default() -> Self { Self::new() }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::with_options(JitOptions::default())
+    }
+
+    pub fn with_options(opts: JitOptions) -> Self {
+        Self { vm: VM::with_options(Rc::new(codegen::compile_module(Vec::new())), opts), optimize: true }
+    }
+
+    /// Parses and compiles `src` without loading it. It fails on any error
+    /// the static checks find, not on their warnings. The program is run
+    /// through the AST optimizer first unless that is turned off.
+    pub fn compile(&self, src: &str) -> Result<Module, Error> {
+        self.compile_with_warnings(src).map(|(module, _)| module)
+    }
+
+    /// Like [`Engine::compile`], also returning the warnings of the static
+    /// checks, so that `src` goes through the front end once.
+    pub fn compile_with_warnings(&self, src: &str) -> Result<(Module, Vec<Diagnostic>), Error> {
+        let mut ast = Parser::new(Lexer::new(src)).parse_program()?;
+        let (errors, warnings): (Vec<Diagnostic>, _) = self.check_ast(&ast).into_iter().partition(|d| d.severity == Severity::Error);
+        if !errors.is_empty() { return Err(Error::Check(errors)); }
+        if self.optimize { optimizer::optimize(&mut ast, &|name| self.vm.global(name).is_some()); }
+        Ok((codegen::compile_module(ast), warnings))
+    }
+
+    /// Parses `src` and returns what the static checks find, warnings included.
+    pub fn check(&self, src: &str) -> Result<Vec<Diagnostic>, Error> {
+        let ast = Parser::new(Lexer::new(src)).parse_program()?;
+        Ok(self.check_ast(&ast))
+    }
+
+    /// Natives and globals are those of this engine at the time of the check.
+    fn check_ast(&self, ast: &[Stmt]) -> Vec<Diagnostic> {
+        checker::check(ast, self.vm.natives(), &|name| self.vm.global(name).is_some())
+    }
+
+    /// Makes `module` the one `call` and `run_main` operate on. Globals are kept.
+    pub fn load(&mut self, module: Module) {
+        self.vm.set_module(Rc::new(module));
+    }
+
+    pub fn module(&self) -> &Module { self.vm.module() }
+
+    /// Runs the top-level statements of the loaded module.
+    pub fn run_main(&mut self) -> Result<(), Error> {
+        self.vm.run_main()?;
+        Ok(())
+    }
+
+    /// Compiles, loads and runs `src`.
+    pub fn eval(&mut self, src: &str) -> Result<(), Error> {
+        let module = self.compile(src)?;
+        self.load(module);
+        self.run_main()
+    }
+
+    pub fn call(&mut self, name: &str, args: &[i64]) -> Result<i64, Error> {
+        let args = args.iter().map(|&n| Value::Int(n)).collect();
+        let v = self.vm.call_function(name, args)?;
+        v.as_int().ok_or_else(|| RuntimeError::TypeError(format!("{name} returned a {} value", v.type_name())).into())
+    }
+
+    /// Tables in the result are only kept alive while the script can reach
+    /// them; store them in a global to hold on to them.
+    pub fn call_value(&mut self, name: &str, args: Vec<Value>) -> Result<Value, Error> {
+        Ok(self.vm.call_function(name, args)?)
+    }
+
+    /// Makes `f` callable from scripts as `name`. Script functions of the same name win.
+    pub fn register_native(&mut self, name: &str, arity: Option<usize>, f: NativeFn) {
+        self.vm.register_native(name, arity, f);
+    }
+
+    /// Like [`Engine::register_native`], for functions the JIT may fold and CSE.
+    pub fn register_pure_native(&mut self, name: &str, arity: Option<usize>, f: NativeFn) {
+        self.vm.register_pure_native(name, arity, f);
+    }
+
+    pub fn global(&self, name: &str) -> Option<i64> {
+        self.vm.global(name).and_then(|v| v.as_int())
+    }
+
+    pub fn set_global(&mut self, name: &str, value: i64) {
+        self.vm.set_global(name, Value::Int(value));
+    }
+
+    pub fn gc_stats(&self) -> &GcStats { self.vm.gc_stats() }
+
+    /// Runs a full collection cycle now.
+    pub fn collect_garbage(&mut self) {
+        self.vm.collect_garbage();
+    }
+
+    pub fn jit_options(&self) -> &JitOptions { &self.vm.jit().opts }
+
+    pub fn set_jit_options(&mut self, opts: JitOptions) {
+        self.vm.jit_mut().opts = opts;
+    }
+
+    pub fn optimize(&self) -> bool { self.optimize }
+
+    /// Turns the AST optimizer on or off for later compiles; see [`optimizer::optimize`].
+    pub fn set_optimize(&mut self, on: bool) {
+        self.optimize = on;
+    }
+
+    /// Limits how deeply script calls may nest; see [`VM::set_max_depth`].
+    pub fn set_max_depth(&mut self, depth: usize) {
+        self.vm.set_max_depth(depth);
+    }
+
+    pub fn vm(&self) -> &VM { &self.vm }
+    pub fn vm_mut(&mut self) -> &mut VM { &mut self.vm }
+}
@@ -0,0 +1,127 @@
+/* ================= Exporters ================= */
+
+use std::fmt::Write;
+
+use crate::codegen::{FunctionProto, Module};
+use crate::ir::{self, IR, IROp, Operand, Ref};
+use crate::jit::Trace;
+use crate::vm::Value;
+
+/// Graphviz DOT of `ir`, a node per instruction labelled with its line of
+/// the textual IR. Solid edges go from each ref operand to its user, dashed
+/// gray ones along the `prev_same_op` skip chains, and every snapshot is a
+/// note the first instruction it covers points to, listing where the
+/// interpreter resumes and the refs it restores.
+pub fn ir_to_dot(ir: &IR, name: &str) -> String {
+    let text = ir.to_string();
+    let mut out = format!("digraph {} {{\n  node [shape=box, fontname=\"monospace\"];\n", dot_str(name));
+    for (i, (ins, label)) in ir.code.iter().zip(text.lines()).enumerate() {
+        let style = if ins.op == IROp::Nop { ", style=dashed, fontcolor=gray" } else { "" };
+        let _ = writeln!(out, "  r{i} [label={}{style}];", dot_str(label));
+        let (a, b) = ir::operands(ins.op);
+        for (kind, r, port) in [(a, ins.a, "a"), (b, ins.b, "b")] {
+            if kind == Operand::Ref && r != Ref::NONE { let _ = writeln!(out, "  r{} -> r{i} [label=\"{port}\"];", r.0); }
+        }
+        if ins.prev_same_op != u16::MAX {
+            let _ = writeln!(out, "  r{i} -> r{} [style=dashed, color=gray, constraint=false];", ins.prev_same_op);
+        }
+    }
+    for (i, (snap, line)) in ir.snapshots.iter().zip(text.lines().skip(ir.code.len())).enumerate() {
+        let _ = writeln!(out, "  snap{i} [shape=note, label={}];", dot_str(line));
+        if (snap.ins.0 as usize) < ir.code.len() {
+            let _ = writeln!(out, "  r{} -> snap{i} [style=dotted, label=\"exit\"];", snap.ins.0);
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn dot_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// JSON of `ir`: `code` lists the instructions as objects with their
+/// `op`, `type`, operands `a` and `b` and `prev_same_op`, `snapshots`
+/// the snapshots. An operand is a ref as a number, `{"sym": name}`,
+/// `{"const": value}`, `{"imm": n}`, or null when unused.
+pub fn ir_to_json(ir: &IR) -> String {
+    let code = ir.code.iter().enumerate().map(|(i, ins)| {
+        let (ka, kb) = ir::operands(ins.op);
+        let operand = |kind, r: Ref| match kind {
+            Operand::Ref if r != Ref::NONE => r.0.to_string(),
+            Operand::Sym => format!("{{\"sym\":{}}}", json_str(ir.sym(r.0))),
+            Operand::Imm => format!("{{\"imm\":{}}}", r.0),
+            Operand::Const => format!("{{\"const\":{}}}", ir.konst(Ref(i as u16)).map_or(String::from("null"), |v| json_value(&v))),
+            _ => String::from("null"),
+        };
+        let prev = if ins.prev_same_op == u16::MAX { String::from("null") } else { ins.prev_same_op.to_string() };
+        format!("{{\"op\":\"{:?}\",\"type\":\"{:?}\",\"a\":{},\"b\":{},\"prev_same_op\":{prev}}}", ins.op, ins.ty, operand(ka, ins.a), operand(kb, ins.b))
+    });
+    let refs = |stack: &[Ref]| json_list(stack.iter().map(|r| r.0.to_string()));
+    let snapshots = ir.snapshots.iter().map(|s| {
+        let callers = s.callers.iter().map(|c| format!("{{\"func\":{},\"pc\":{},\"stack\":{}}}", json_str(&c.func), c.pc, refs(&c.stack)));
+        format!("{{\"ins\":{},\"func\":{},\"pc\":{},\"stack\":{},\"callers\":{}}}", s.ins.0, json_str(&s.func), s.pc, refs(&s.stack), json_list(callers))
+    });
+    format!("{{\"code\":{},\"snapshots\":{}}}", json_list(code), json_list(snapshots))
+}
+
+/// JSON of a trace: its `name`, the function `func` and `pc` it starts
+/// at, and its `ir` as [`ir_to_json`] writes it.
+pub fn trace_to_json(trace: &Trace) -> String {
+    format!("{{\"name\":{},\"func\":{},\"pc\":{},\"ir\":{}}}", json_str(&trace.name), json_str(&trace.func), trace.pc, ir_to_json(&trace.ir))
+}
+
+/// JSON of a module: `main` and the other prototypes under `functions` by
+/// name, each with its signature, the line it is defined on, its code as
+/// the disassembler lists instructions, the source line of each, and the
+/// names of the functions defined in it.
+pub fn module_to_json(module: &Module) -> String {
+    let mut names: Vec<&String> = module.funs.keys().collect();
+    names.sort();
+    let funs = names.iter().map(|n| format!("{}:{}", json_str(n), proto_to_json(&module.funs[*n])));
+    format!("{{\"main\":{},\"functions\":{{{}}}}}", proto_to_json(&module.main), funs.collect::<Vec<_>>().join(","))
+}
+
+fn proto_to_json(f: &FunctionProto) -> String {
+    let strs = |v: &[String]| json_list(v.iter().map(|s| json_str(s)));
+    format!(
+        "{{\"name\":{},\"params\":{},\"variadic\":{},\"upvals\":{},\"line\":{},\"code\":{},\"lines\":{},\"children\":{}}}",
+        json_str(&f.name), strs(&f.params), f.variadic, strs(&f.upvals), f.line,
+        json_list(f.code.iter().map(|op| json_str(&op.to_string()))),
+        json_list(f.lines.iter().map(usize::to_string)),
+        json_list(f.children.iter().map(|c| json_str(&c.name))),
+    )
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c < ' ' => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A constant as JSON; floats JSON has no number for are strings.
+fn json_value(v: &Value) -> String {
+    match v {
+        Value::Int(n) => n.to_string(),
+        Value::Float(x) if x.is_finite() => format!("{x:?}"),
+        Value::Float(x) => json_str(&format!("{x:?}")),
+        Value::Str(s) => json_str(s),
+        Value::Bool(b) => b.to_string(),
+        _ => String::from("null"),
+    }
+}
@@ -0,0 +1,224 @@
+/* ================= Formatter ================= */
+
+use crate::cst::{Element, Kind, Leaf, Node};
+use crate::lexer::{Lexer, Token};
+use crate::parser::{PResult, Parser};
+
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// One level of indentation.
+    pub indent: String,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self { Self { indent: String::from("    ") } }
+}
+
+/// Pretty-prints `src` canonically: a statement per line, blocks indented,
+/// single spaces around operators and after commas, no trailing commas.
+/// Comments stay where they were, on their own line or after a token, and
+/// single blank lines between statements are kept. Formatted source comes
+/// back unchanged.
+pub fn format(src: &str, opts: &FormatOptions) -> PResult<String> {
+    let mut parser = Parser::with_cst(Lexer::new(src));
+    parser.parse_program()?;
+    let tree = parser.syntax_tree().expect("a parsed program has a syntax tree");
+    let mut f = Formatter { opts, out: String::new(), level: 0, gap: Gap::None, continued: false };
+    f.node(&tree);
+    Ok(f.out)
+}
+
+/// What goes before the next thing written, the most asked for winning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Gap { None, Space, Line, BlankLine }
+
+struct Formatter<'a> {
+    opts: &'a FormatOptions,
+    out: String,
+    level: usize,
+    gap: Gap,
+    // whether a statement is under way, so that a line a comment breaks in
+    // it is indented one level more
+    continued: bool,
+}
+
+fn is(e: &Element, t: &Token) -> bool {
+    matches!(e, Element::Leaf(l) if &l.token == t)
+}
+
+/// Whether `trivia` holds a comment a line break follows or goes before.
+fn breaks_line(trivia: &str) -> bool {
+    trivia.contains("//") || (trivia.contains("/*") && trivia.contains('\n'))
+}
+
+/// Whether `node` takes more than one line: it holds a block with
+/// statements, or a comment that breaks a line. The trivia before its first
+/// token is not part of it.
+fn breaks(node: &Node) -> bool {
+    fn walk<'a>(node: &'a Node, leaves: &mut Vec<&'a Leaf>) -> bool {
+        let mut full = node.kind == Kind::Block && node.children.len() > 2;
+        for c in &node.children {
+            match c {
+                Element::Node(n) => full |= walk(n, leaves),
+                Element::Leaf(l) => leaves.push(l),
+            }
+        }
+        full
+    }
+    let mut leaves = Vec::new();
+    walk(node, &mut leaves) || leaves.iter().skip(1).any(|l| breaks_line(&l.trivia))
+}
+
+/// Gap between two children of a `kind` node.
+fn gap(kind: Kind, prev: &Element, cur: &Element) -> Gap {
+    use Token::*;
+    let tight = [Semicolon, Comma, RParen, RBracket, Dot].iter().any(|t| is(cur, t))
+        || [LParen, LBracket, Dot].iter().any(|t| is(prev, t))
+        || (kind == Kind::Index && is(cur, &LBracket))
+        || matches!(cur, Element::Node(n) if matches!(n.kind, Kind::Args | Kind::Params))
+        || (is(prev, &LBrace) && is(cur, &RBrace));
+    if tight { Gap::None } else { Gap::Space }
+}
+
+impl Formatter<'_> {
+    fn want(&mut self, gap: Gap) { self.gap = self.gap.max(gap); }
+
+    fn flush(&mut self) {
+        match std::mem::replace(&mut self.gap, Gap::None) {
+            Gap::None => {}
+            Gap::Space => self.out.push(' '),
+            // nothing goes before the first line, no blank line after a `{`
+            _ if self.out.is_empty() => {}
+            gap => {
+                if gap == Gap::BlankLine && !self.out.ends_with('{') { self.out.push('\n'); }
+                self.out.push('\n');
+                for _ in 0..self.level + self.continued as usize { self.out.push_str(&self.opts.indent); }
+            }
+        }
+    }
+
+    /// Writes the comments in the trivia of `leaf`. One with no line break
+    /// before it stays after the token it followed; the others get a line
+    /// each, after a blank line if they had one.
+    fn comments(&mut self, leaf: &Leaf) {
+        let mut rest = leaf.trivia.as_str();
+        let mut after_comment = false;
+        loop {
+            let comment = rest.trim_start();
+            let newlines = rest[..rest.len() - comment.len()].matches('\n').count();
+            // what follows a comment keeps the line break after it
+            if after_comment && newlines > 0 { self.want(Gap::Line); }
+            if newlines >= 2 && self.gap >= Gap::Line { self.gap = Gap::BlankLine; }
+            if comment.is_empty() { return; }
+            let len = if comment.starts_with("//") {
+                comment.find('\n').unwrap_or(comment.len())
+            } else {
+                comment.find("*/").map_or(comment.len(), |i| i + 2)
+            };
+            if newlines == 0 && !self.out.is_empty() {
+                if self.gap != Gap::None || !self.out.ends_with(['(', '[']) { self.out.push(' '); }
+            } else {
+                self.want(Gap::Line);
+                self.flush();
+            }
+            self.out.push_str(comment[..len].trim_end());
+            if comment.starts_with("//") {
+                self.want(Gap::Line);
+            } else if !matches!(leaf.token, Token::RParen | Token::RBracket | Token::Comma | Token::Semicolon) {
+                self.want(Gap::Space);
+            }
+            rest = &comment[len..];
+            after_comment = true;
+        }
+    }
+
+    fn token(&mut self, leaf: &Leaf) {
+        self.comments(leaf);
+        self.flush();
+        self.out.push_str(&leaf.text);
+        self.continued = true;
+    }
+
+    /// Starts a statement on a line of its own.
+    fn stmt(&mut self, stmt: &Element) {
+        self.want(Gap::Line);
+        self.continued = false;
+        self.element(stmt);
+    }
+
+    fn element(&mut self, e: &Element) {
+        match e {
+            Element::Node(n) => self.node(n),
+            Element::Leaf(l) => self.token(l),
+        }
+    }
+
+    /// Ends a block or table opened a level deeper, with `close` on a line
+    /// of its own.
+    fn close(&mut self, close: &Leaf) {
+        self.continued = false;
+        self.comments(close);
+        self.level -= 1;
+        self.gap = Gap::Line;
+        self.flush();
+        self.out.push_str(&close.text);
+        self.continued = true;
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node.kind {
+            Kind::Program => {
+                for c in &node.children {
+                    match c {
+                        Element::Node(_) => self.stmt(c),
+                        // EOF
+                        Element::Leaf(l) => { self.continued = false; self.comments(l); }
+                    }
+                }
+                if !self.out.is_empty() { self.out.push('\n'); }
+            }
+            Kind::Block => {
+                let [Element::Leaf(open), stmts @ .., Element::Leaf(close)] = &node.children[..] else { unreachable!("a block is braced") };
+                self.token(open);
+                // an empty block stays on one line, with its comments
+                if stmts.is_empty() && !breaks_line(&close.trivia) {
+                    self.comments(close);
+                    self.flush();
+                    self.out.push_str(&close.text);
+                    return;
+                }
+                self.level += 1;
+                for s in stmts { self.stmt(s); }
+                self.close(close);
+            }
+            Kind::Table if breaks(node) => {
+                // a table that cannot go on one line gets a line per field
+                let [Element::Leaf(open), fields @ .., Element::Leaf(close)] = &node.children[..] else { unreachable!("a table is braced") };
+                self.token(open);
+                self.level += 1;
+                for (i, c) in fields.iter().enumerate() {
+                    match c {
+                        // a trailing comma goes
+                        Element::Leaf(l) if l.token == Token::Comma && i + 1 == fields.len() => self.comments(l),
+                        Element::Leaf(l) if l.token == Token::Comma => self.token(l),
+                        _ => self.stmt(c),
+                    }
+                }
+                self.close(close);
+            }
+            kind => {
+                let children = &node.children;
+                for (i, c) in children.iter().enumerate() {
+                    if i > 0 { self.want(gap(kind, &children[i - 1], c)); }
+                    // a trailing comma in a table goes
+                    if kind == Kind::Table && is(c, &Token::Comma) && children.get(i + 1).is_some_and(|n| is(n, &Token::RBrace)) {
+                        let Element::Leaf(l) = c else { unreachable!() };
+                        self.comments(l);
+                        continue;
+                    }
+                    self.element(c);
+                }
+            }
+        }
+    }
+}
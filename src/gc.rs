@@ -0,0 +1,265 @@
+/* ================= Garbage collector ================= */
+
+use std::rc::Rc;
+
+use crate::codegen::FunctionProto;
+use crate::table::Table;
+use crate::vm::Value;
+
+/// Handle to a heap object. Copying it does not keep the object alive, only
+/// being reachable from the roots the VM marks does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GcRef(u32);
+
+impl GcRef {
+    pub fn index(self) -> usize { self.0 as usize }
+}
+
+/// A function value: its prototype plus the cells of the variables it captured.
+#[derive(Debug)]
+pub struct Closure {
+    pub proto: Rc<FunctionProto>,
+    /// One cell per name in `proto.upvals`.
+    pub upvals: Vec<GcRef>,
+}
+
+#[derive(Debug)]
+pub enum Object {
+    Table(Table),
+    Closure(Closure),
+    /// A captured variable, shared by the frame that owns it and its closures.
+    Cell(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Color {
+    /// Not reached yet; still white at the end of marking means garbage.
+    White,
+    /// Reached, children not traversed yet.
+    Gray,
+    /// Reached and traversed.
+    Black,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Idle,
+    Mark,
+    /// Sweeping slots from the given index on.
+    Sweep(usize),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    /// Completed collection cycles.
+    pub cycles: u64,
+    /// Incremental steps taken.
+    pub steps: u64,
+    /// Objects allocated in total.
+    pub allocated: u64,
+    /// Objects freed in total.
+    pub freed: u64,
+    /// Objects alive after the last cycle, plus those allocated since.
+    pub live: usize,
+    /// Interned strings nothing referred to any more.
+    pub strings_freed: u64,
+}
+
+// Units of work (objects traversed or slots swept) per incremental step.
+pub(crate) const STEP_WORK: usize = 64;
+// A cycle starts once this many objects were allocated since the last one...
+const MIN_THRESHOLD: usize = 256;
+// ...or once the heap grew by this percentage of what survived it.
+const PAUSE: usize = 100;
+
+/// Incremental tri-color mark-and-sweep heap. It owns every table, closure
+/// and upvalue cell; values refer to them through [`GcRef`]s.
+///
+/// The collector knows nothing about roots: the VM marks them with
+/// [`Heap::mark`] when a cycle starts and again, atomically, before it
+/// sweeps. Writes into a black table must go through [`Heap::barrier`].
+pub struct Heap {
+    slots: Vec<Option<Object>>,
+    colors: Vec<Color>,
+    free: Vec<u32>,
+    gray: Vec<u32>,
+    phase: Phase,
+    // allocations since the last cycle finished
+    debt: usize,
+    threshold: usize,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            colors: Vec::new(),
+            free: Vec::new(),
+            gray: Vec::new(),
+            phase: Phase::Idle,
+            debt: 0,
+            threshold: MIN_THRESHOLD,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl Heap {
+    pub fn alloc(&mut self, o: Object) -> GcRef {
+        let i = match self.free.pop() {
+            Some(i) => { self.slots[i as usize] = Some(o); i }
+            None => {
+                self.slots.push(Some(o));
+                self.colors.push(Color::White);
+                (self.slots.len() - 1) as u32
+            }
+        };
+        // objects born during marking are traversed like any reached one, since
+        // they may already hold white values; the sweeper must skip those born
+        // ahead of it this cycle, and whiten them as it passes
+        self.colors[i as usize] = match self.phase {
+            Phase::Idle => Color::White,
+            Phase::Mark => { self.gray.push(i); Color::Gray }
+            Phase::Sweep(at) => if (i as usize) < at { Color::White } else { Color::Black },
+        };
+        self.debt += 1;
+        self.stats.allocated += 1;
+        self.stats.live += 1;
+        GcRef(i)
+    }
+
+    pub fn get(&self, r: GcRef) -> &Object {
+        self.slots[r.index()].as_ref().expect("dangling GcRef")
+    }
+
+    /// Mutable access without a write barrier; see [`Heap::barrier`].
+    pub fn get_mut(&mut self, r: GcRef) -> &mut Object {
+        self.slots[r.index()].as_mut().expect("dangling GcRef")
+    }
+
+    pub fn table(&self, r: GcRef) -> &Table {
+        match self.get(r) { Object::Table(t) => t, o => panic!("expected a table, got {o:?}") }
+    }
+
+    pub fn table_mut(&mut self, r: GcRef) -> &mut Table {
+        match self.get_mut(r) { Object::Table(t) => t, o => panic!("expected a table, got {o:?}") }
+    }
+
+    pub fn closure(&self, r: GcRef) -> &Closure {
+        match self.get(r) { Object::Closure(c) => c, o => panic!("expected a closure, got {o:?}") }
+    }
+
+    pub fn cell(&self, r: GcRef) -> &Value {
+        match self.get(r) { Object::Cell(v) => v, o => panic!("expected a cell, got {o:?}") }
+    }
+
+    pub fn cell_mut(&mut self, r: GcRef) -> &mut Value {
+        match self.get_mut(r) { Object::Cell(v) => v, o => panic!("expected a cell, got {o:?}") }
+    }
+
+    /// Backward barrier for a store into `r`: a black object that gains a
+    /// reference goes back to gray, to be traversed again.
+    pub fn barrier(&mut self, r: GcRef) {
+        if self.phase == Phase::Mark && self.colors[r.index()] == Color::Black {
+            self.colors[r.index()] = Color::Gray;
+            self.gray.push(r.0);
+        }
+    }
+
+    /// Shades a root (or a child) gray if it is a white heap object.
+    pub fn mark(&mut self, v: &Value) {
+        if let Value::Table(r) | Value::Function(r) = v { self.mark_ref(*r); }
+    }
+
+    pub fn mark_ref(&mut self, r: GcRef) {
+        if self.colors[r.index()] == Color::White {
+            self.colors[r.index()] = Color::Gray;
+            self.gray.push(r.0);
+        }
+    }
+
+    pub fn phase(&self) -> Phase { self.phase }
+    pub fn stats(&self) -> &GcStats { &self.stats }
+
+    /// Whether the VM should take a collector step at its next safe point.
+    pub fn wants_step(&self) -> bool {
+        self.phase != Phase::Idle || self.debt >= self.threshold
+    }
+
+    /// Starts a cycle; the caller marks the roots next.
+    pub fn start(&mut self) {
+        debug_assert_eq!(self.phase, Phase::Idle);
+        self.phase = Phase::Mark;
+    }
+
+    /// Does one increment of marking. Returns true once the gray list is
+    /// empty, at which point the caller re-marks the roots and calls
+    /// [`Heap::finish_mark`].
+    pub fn propagate(&mut self, mut work: usize) -> bool {
+        self.stats.steps += 1;
+        while work > 0 && let Some(i) = self.gray.pop() {
+            self.traverse(i);
+            work -= 1;
+        }
+        self.gray.is_empty()
+    }
+
+    fn traverse(&mut self, i: u32) {
+        self.colors[i as usize] = Color::Black;
+        let Some(o) = self.slots[i as usize].take() else { return };
+        match &o {
+            Object::Table(t) => t.values().for_each(|v| self.mark(v)),
+            Object::Closure(c) => c.upvals.iter().for_each(|&r| self.mark_ref(r)),
+            Object::Cell(v) => self.mark(v),
+        }
+        self.slots[i as usize] = Some(o);
+    }
+
+    /// Atomic end of marking: drains the gray list the root re-marking
+    /// refilled and starts sweeping.
+    pub fn finish_mark(&mut self) {
+        while let Some(i) = self.gray.pop() {
+            self.traverse(i);
+        }
+        self.phase = Phase::Sweep(0);
+    }
+
+    /// Sweeps a batch of slots, freeing white objects and whitening black
+    /// ones for the next cycle. Returns true once the cycle is complete.
+    pub fn sweep(&mut self, work: usize) -> bool {
+        let Phase::Sweep(at) = self.phase else { return true };
+        self.stats.steps += 1;
+        let end = at.saturating_add(work).min(self.slots.len());
+        for i in at..end {
+            if self.slots[i].is_none() { continue; }
+            if self.colors[i] == Color::White {
+                self.slots[i] = None;
+                self.free.push(i as u32);
+                self.stats.freed += 1;
+                self.stats.live -= 1;
+            } else {
+                self.colors[i] = Color::White;
+            }
+        }
+        if end < self.slots.len() {
+            self.phase = Phase::Sweep(end);
+            return false;
+        }
+        self.phase = Phase::Idle;
+        self.stats.cycles += 1;
+        self.debt = 0;
+        self.threshold = (self.stats.live * PAUSE / 100).max(MIN_THRESHOLD);
+        true
+    }
+
+    /// Counts an allocation made outside the heap, like a new interned string,
+    /// towards starting the next cycle.
+    pub(crate) fn add_debt(&mut self, n: usize) {
+        self.debt += n;
+    }
+
+    pub(crate) fn note_strings_freed(&mut self, n: usize) {
+        self.stats.strings_freed += n as u64;
+    }
+}
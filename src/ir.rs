@@ -1,6 +1,11 @@
 /* ================= IR Instructions ================= */
 
 use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::CmpOp;
+use crate::vm::Value;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -9,17 +14,160 @@ pub enum IROp {
     Add=1,
     LoadVar=2,
     StoreVar=3,
-    Print=4
+    Print=4,
+    Ret=5,
+    CArg=6,   // argument list cell: a = earlier args, b = next arg
+    CallN=7,  // call to a pure native: a = args, b = symbol
+    CallS=8,  // call to a native with side effects
+    Abs=9,
+    Min=10,
+    Max=11,
+    Pow=12,
+    KPri=13,       // nil/false/true, told apart by type and a
+    Not=14,
+    GuardTrue=15,  // exit unless a is truthy
+    GuardFalse=16, // exit unless a is falsy
+    KNum=17,
+    Conv=18,       // int -> float
+    AddOv=19,      // int add, exits when the result overflows
+    KStr=20,
+    StrCat=21,     // a .. b, allocates a new string
+    Eq=22,
+    Ne=23,
+    Lt=24,
+    Le=25,
+    Gt=26,
+    Ge=27,
+    TNew=28,   // new table: a = array size hint
+    ABC=29,    // exit unless b indexes the array part of table a
+    ARef=30,   // array slot b of table a
+    ALoad=31,  // load from ARef a, guarded on its type
+    AStore=32, // store b into ARef a
+    HRef=33,   // slot for key b of table a, any key type
+    HLoad=34,  // load from HRef a, guarded on its type
+    HStore=35, // store b into HRef a
+    FNew=36,       // closure over prototype a (a symbol)
+    GuardFn=37,    // exit unless a is a closure over prototype b
+    FrameEnter=38, // push a frame for closure a, entering an inlined call
+    FrameLeave=39, // pop it again on the inlined return
+    SLoad=40,      // operand stack slot a an up-recursion trace starts with, guarded on its type
+    Cont=41,       // down-recursion: keep the frames this instruction's snapshot describes for the return
+    FrameSwap=42,  // tail call: replace the current frame with a fresh one for closure a, returning one result if b = 1
+    Loop=43,       // back to the first instruction
+    RetN=44,       // return the values of CArg list a, none for a = NONE
+    Nop=45         // left behind by DCE
+}
+
+impl IROp {
+    const ALL: [IROp; 46] = {
+        use IROp::*;
+        [KInt, Add, LoadVar, StoreVar, Print, Ret, CArg, CallN, CallS, Abs, Min, Max, Pow, KPri, Not, GuardTrue,
+         GuardFalse, KNum, Conv, AddOv, KStr, StrCat, Eq, Ne, Lt, Le, Gt, Ge, TNew, ABC, ARef, ALoad, AStore, HRef,
+         HLoad, HStore, FNew, GuardFn, FrameEnter, FrameLeave, SLoad, Cont, FrameSwap, Loop, RetN, Nop]
+    };
+
+    pub fn compare(op: CmpOp) -> IROp {
+        match op {
+            CmpOp::Eq => IROp::Eq,
+            CmpOp::Ne => IROp::Ne,
+            CmpOp::Lt => IROp::Lt,
+            CmpOp::Le => IROp::Le,
+            CmpOp::Gt => IROp::Gt,
+            CmpOp::Ge => IROp::Ge,
+        }
+    }
+
+    /// Whether the instruction is there for more than its value: it has an
+    /// effect, or it is a guard. Only those without one can be dead, and
+    /// only if they cannot fail either; see [`IR::may_fail`].
+    pub fn has_effect(self) -> bool {
+        !matches!(self,
+            IROp::KInt | IROp::KNum | IROp::KStr | IROp::KPri | IROp::Add | IROp::Conv | IROp::CArg | IROp::CallN
+            | IROp::Abs | IROp::Min | IROp::Max | IROp::Pow | IROp::Not | IROp::StrCat
+            | IROp::Eq | IROp::Ne | IROp::Lt | IROp::Le | IROp::Gt | IROp::Ge
+            | IROp::ARef | IROp::HRef | IROp::TNew | IROp::FNew | IROp::Nop)
+    }
+
+    /// Whether operands a and b are refs, rather than constants, symbols,
+    /// slots or unused.
+    pub fn ref_operands(self) -> (bool, bool) {
+        match self {
+            IROp::KInt | IROp::KNum | IROp::KStr | IROp::KPri | IROp::LoadVar | IROp::TNew | IROp::FNew | IROp::SLoad
+            | IROp::FrameLeave | IROp::Cont | IROp::Loop | IROp::Nop => (false, false),
+            IROp::CallN | IROp::CallS | IROp::GuardFn | IROp::Print | IROp::Ret | IROp::RetN | IROp::Abs | IROp::Not
+            | IROp::GuardTrue | IROp::GuardFalse | IROp::Conv | IROp::ALoad | IROp::HLoad | IROp::FrameEnter
+            | IROp::FrameSwap => (true, false),
+            IROp::StoreVar => (false, true),
+            _ => (true, true),
+        }
+    }
+
+    pub fn as_compare(self) -> Option<CmpOp> {
+        Some(match self {
+            IROp::Eq => CmpOp::Eq,
+            IROp::Ne => CmpOp::Ne,
+            IROp::Lt => CmpOp::Lt,
+            IROp::Le => CmpOp::Le,
+            IROp::Gt => CmpOp::Gt,
+            IROp::Ge => CmpOp::Ge,
+            _ => return None,
+        })
+    }
 }
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IRType {
     Int=0,
-    Any=1
+    Any=1,
+    Bool=2,
+    Nil=3,
+    Float=4,
+    Str=5,
+    Table=6,
+    Function=7
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+impl IRType {
+    const ALL: [IRType; 8] = [IRType::Int, IRType::Any, IRType::Bool, IRType::Nil, IRType::Float, IRType::Str, IRType::Table, IRType::Function];
+
+    pub fn of(v: &Value) -> IRType {
+        match v {
+            Value::Int(_) => IRType::Int,
+            Value::Float(_) => IRType::Float,
+            Value::Str(_) => IRType::Str,
+            Value::Table(_) => IRType::Table,
+            Value::Function(_) => IRType::Function,
+            Value::Bool(_) => IRType::Bool,
+            Value::Nil => IRType::Nil,
+        }
+    }
+}
+
+/// Interpreter state to resume from when a guard fails: the bytecode pc
+/// and the refs holding each operand stack slot. Like LuaJIT, a snapshot
+/// covers the guards from `ins` up to the next snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub ins: Ref,
+    /// Function the pc is in: the traced one, or a call inlined into it.
+    pub func: String,
+    pub pc: usize,
+    pub stack: Vec<Ref>,
+    /// Frames of the inlined calls `func` is nested in, outermost first.
+    pub callers: Vec<SnapFrame>,
+}
+
+/// A caller frame of an inlined call, to resume once the call returns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapFrame {
+    pub func: String,
+    /// The pc after the call; the result is pushed onto `stack` first.
+    pub pc: usize,
+    pub stack: Vec<Ref>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Ref(pub u16);
 impl Ref {pub const NONE: Ref = Ref(u16::MAX); }
 
@@ -36,19 +184,33 @@ pub struct IRIns {
 pub struct IR {
     pub code: Vec<IRIns>,
     pub last_of_op: [u16; 256],
+    pub snapshots: Vec<Snapshot>,
     const_pool: Vec<i64>,
     const_map: HashMap<i64, Ref>,
+    knum_pool: Vec<f64>,
+    knum_map: HashMap<u64, Ref>, // keyed by bit pattern so -0.0 and NaN intern too
+    kstr_pool: Vec<Rc<str>>,
+    kstr_map: HashMap<Rc<str>, Ref>,
     sym_pool: Vec<String>,
     sym_map: HashMap<String,u16>
 }
 
+impl Default for IR {
+    fn default() -> Self { Self::new() }
+}
+
 impl IR {
     pub fn new() -> Self {
         Self {
             code: Vec::new(),
             last_of_op: [u16::MAX; 256],
+            snapshots: Vec::new(),
             const_pool: Vec::new(),
             const_map: HashMap::new(),
+            knum_pool: Vec::new(),
+            knum_map: HashMap::new(),
+            kstr_pool: Vec::new(),
+            kstr_map: HashMap::new(),
             sym_pool: Vec::new(),
             sym_map: HashMap::new(),
         }
@@ -63,6 +225,56 @@ impl IR {
         id
     }
 
+    /// Dead code elimination: instructions without an effect whose value no
+    /// live instruction or snapshot uses become Nops. Refs keep pointing at
+    /// the same slots, and the trace keeps them for the values it builds.
+    pub fn dce(&mut self) {
+        let mut live = vec![false; self.code.len()];
+        for snap in &self.snapshots {
+            let frames = snap.callers.iter().flat_map(|f| &f.stack);
+            for r in snap.stack.iter().chain(frames) { live[r.0 as usize] = true; }
+        }
+        // operands always come before their uses
+        for i in (0..self.code.len()).rev() {
+            if !live[i] && !self.code[i].op.has_effect() && !self.may_fail(&self.code[i]) {
+                self.code[i].op = IROp::Nop;
+                continue;
+            }
+            let ins = &self.code[i];
+            let (a, b) = ins.op.ref_operands();
+            for (used, r) in [(a, ins.a), (b, ins.b)] {
+                if used && r != Ref::NONE { live[r.0 as usize] = true; }
+            }
+        }
+    }
+
+    /// Whether `ins` may raise an error when it runs, as arithmetic,
+    /// concatenation and ordering do on operands of the wrong types. Those
+    /// are known only for operands typed more precisely than `Any`. A pure
+    /// native call may fail on any arguments.
+    pub fn may_fail(&self, ins: &IRIns) -> bool {
+        let ty = |r: Ref| self.code[r.0 as usize].ty;
+        let num = |r| matches!(ty(r), IRType::Int | IRType::Float);
+        let str = |r| ty(r) == IRType::Str;
+        let (a, b) = (ins.a, ins.b);
+        match ins.op {
+            IROp::Add | IROp::Min | IROp::Max | IROp::Pow => !(num(a) && num(b)),
+            IROp::Abs => !num(a),
+            IROp::StrCat => !((num(a) || str(a)) && (num(b) || str(b))),
+            IROp::Lt | IROp::Le | IROp::Gt | IROp::Ge => !((num(a) && num(b)) || (str(a) && str(b))),
+            IROp::CallN => true,
+            _ => false,
+        }
+    }
+
+    /// Snapshot a guard at `r` exits through.
+    pub fn snapshot_for(&self, r: Ref) -> Option<&Snapshot> {
+        let n = self.snapshots.partition_point(|s| s.ins.0 <= r.0);
+        n.checked_sub(1).map(|i| &self.snapshots[i])
+    }
+
+    pub fn sym(&self, id: u16) -> &str { &self.sym_pool[id as usize] }
+
     pub fn push(&mut self, mut ins: IRIns) -> Ref {
         // Want to record the last of this op for the skip list
         let idx = self.code.len() as u16;
@@ -93,6 +305,64 @@ impl IR {
         r
     }
 
+    pub fn emit_knum(&mut self, x: f64) -> Ref {
+        if let Some(&r) = self.knum_map.get(&x.to_bits()) { return r; }
+
+        let kid = self.knum_pool.len() as u16;
+        self.knum_pool.push(x);
+        let r = self.push(IRIns {
+            op: IROp::KNum,
+            ty: IRType::Float,
+            a: Ref(kid),
+            b: Ref::NONE,
+            prev_same_op: u16::MAX});
+        self.knum_map.insert(x.to_bits(), r);
+        r
+    }
+
+    /// `s` should be the VM's interned copy, so the trace hands out the same string.
+    pub fn emit_kstr(&mut self, s: Rc<str>) -> Ref {
+        if let Some(&r) = self.kstr_map.get(&s) { return r; }
+
+        let kid = self.kstr_pool.len() as u16;
+        self.kstr_pool.push(Rc::clone(&s));
+        let r = self.push(IRIns {
+            op: IROp::KStr,
+            ty: IRType::Str,
+            a: Ref(kid),
+            b: Ref::NONE,
+            prev_same_op: u16::MAX});
+        self.kstr_map.insert(s, r);
+        r
+    }
+
+    pub fn emit_kpri(&mut self, v: &Value) -> Ref {
+        let (ty, a) = match v {
+            Value::Bool(b) => (IRType::Bool, *b as u16),
+            _ => (IRType::Nil, 0),
+        };
+        let mut prev = self.last_of_op[IROp::KPri as usize];
+        while prev != u16::MAX {
+            let ins = &self.code[prev as usize];
+            if ins.ty == ty && ins.a.0 == a { return Ref(prev); }
+            prev = ins.prev_same_op;
+        }
+        self.push(IRIns { op: IROp::KPri, ty, a: Ref(a), b: Ref::NONE, prev_same_op: u16::MAX })
+    }
+
+    /// Value of a `KInt`, `KNum`, `KStr` or `KPri`.
+    pub fn konst(&self, r: Ref) -> Option<Value> {
+        let ins = self.code.get(r.0 as usize)?;
+        match (ins.op, ins.ty) {
+            (IROp::KInt, _) => self.const_value(r).map(Value::Int),
+            (IROp::KNum, _) => Some(Value::Float(self.knum_pool[ins.a.0 as usize])),
+            (IROp::KStr, _) => Some(Value::Str(Rc::clone(&self.kstr_pool[ins.a.0 as usize]))),
+            (IROp::KPri, IRType::Bool) => Some(Value::Bool(ins.a.0 != 0)),
+            (IROp::KPri, _) => Some(Value::Nil),
+            _ => None,
+        }
+    }
+
     pub fn const_value(&self, r: Ref) -> Option<i64> {
         let i = r.0 as usize;
         if i >= self.code.len() { return None; }
@@ -105,39 +375,253 @@ impl IR {
     }
 }
 
-fn dump_ir(ir: &IR) {
-    println!("\n== IR (linear, pointer-free, typed) ==");
-    for (i, ins) in ir.code.iter().enumerate() {
-        let prev = if ins.prev_same_op == u16::MAX { String::from("∅") } else { ins.prev_same_op.to_string() };
-        let show_a = |ins: &IRIns| -> String {
-            match ins.op {
-                IROp::KInt => format!("#{}", ir.const_pool[ins.a.0 as usize]),
-                IROp::LoadVar | IROp::StoreVar => {
-                    let sym = &ir.sym_pool[ins.a.0 as usize];
-                    if ins.op == IROp::StoreVar { format!("{} <- r{}", sym, ins.b.0) } else { sym.to_string() }
+/* ================= Textual IR ================= */
+
+/// What an operand of an instruction holds, for reading and writing it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operand { Unused, Ref, Sym, Const, Imm }
+
+pub(crate) fn operands(op: IROp) -> (Operand, Operand) {
+    use Operand::*;
+    match op {
+        IROp::KInt | IROp::KNum | IROp::KStr | IROp::KPri => (Const, Unused),
+        IROp::TNew | IROp::SLoad => (Imm, Unused),
+        IROp::FrameSwap => (Ref, Imm),
+        IROp::LoadVar | IROp::FNew => (Sym, Unused),
+        IROp::StoreVar => (Sym, Ref),
+        IROp::CallN | IROp::CallS | IROp::GuardFn => (Ref, Sym),
+        _ => match op.ref_operands() {
+            (true, true) => (Ref, Ref),
+            (true, false) => (Ref, Unused),
+            _ => (Unused, Unused),
+        },
+    }
+}
+
+/// The text `IR::parse` reads back into the same IR: an instruction a line,
+///
+/// ```text
+/// 0002 AddOv Int r0 r1
+/// ```
+///
+/// as its index, op, type and operands: refs as `rN`, or `-` for none,
+/// symbols as `@name`, constants as `#1`, `#1.5`, `#"s"`, `#true` or
+/// `#nil`, and counts and slots as `#N`. The snapshots follow, one a line,
+///
+/// ```text
+/// snap 0002 f pc=3 [r0 r1] caller main pc=7 [r4]
+/// ```
+///
+/// with the instruction they start at, the function and pc to resume at,
+/// the refs of the stack slots, and any caller frames, outermost first.
+impl fmt::Display for IR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, ins) in self.code.iter().enumerate() {
+            write!(f, "{i:04} {:?} {:?}", ins.op, ins.ty)?;
+            let (a, b) = operands(ins.op);
+            for (kind, r) in [(a, ins.a), (b, ins.b)] {
+                match kind {
+                    Operand::Unused => {}
+                    Operand::Ref if r == Ref::NONE => write!(f, " -")?,
+                    Operand::Ref => write!(f, " r{}", r.0)?,
+                    Operand::Sym => write!(f, " @{}", self.sym(r.0))?,
+                    Operand::Imm => write!(f, " #{}", r.0)?,
+                    Operand::Const => match self.konst(Ref(i as u16)) {
+                        Some(Value::Str(s)) => write!(f, " #{s:?}")?,
+                        Some(Value::Float(x)) => write!(f, " #{x:?}")?,
+                        Some(v) => write!(f, " #{v}")?,
+                        None => write!(f, " -")?,
+                    },
                 }
-                _ => format!("r{}", ins.a.0),
             }
-        };
-        let show_b = |ins: &IRIns| -> String {
-            match ins.op {
-                IROp::Add => format!("r{}", ins.b.0),
-                _ => String::from("-"),
+            writeln!(f)?;
+        }
+        let slots = |stack: &[Ref]| stack.iter().map(|r| format!("r{}", r.0)).collect::<Vec<_>>().join(" ");
+        for snap in &self.snapshots {
+            write!(f, "snap {:04} {} pc={} [{}]", snap.ins.0, snap.func, snap.pc, slots(&snap.stack))?;
+            for c in &snap.callers { write!(f, " caller {} pc={} [{}]", c.func, c.pc, slots(&c.stack))?; }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for IrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for IrParseError {}
+
+/// Reads the words of a line of textual IR; `;` starts a comment.
+struct Cursor<'a> { rest: &'a str }
+
+impl<'a> Cursor<'a> {
+    fn done(&mut self) -> bool {
+        self.rest = self.rest.trim_start();
+        self.rest.is_empty() || self.rest.starts_with(';')
+    }
+
+    fn eat(&mut self, s: &str) -> bool {
+        self.rest = self.rest.trim_start();
+        self.rest.strip_prefix(s).map(|r| self.rest = r).is_some()
+    }
+
+    /// Text up to whitespace, a bracket or a comment.
+    fn word(&mut self) -> Result<&'a str, String> {
+        self.rest = self.rest.trim_start();
+        let end = self.rest.find(|c: char| c.is_whitespace() || matches!(c, '[' | ']' | ';')).unwrap_or(self.rest.len());
+        if end == 0 { return Err(String::from("unexpected end of line")); }
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(word)
+    }
+
+    /// The rest of a string constant after its opening quote, with the
+    /// escapes `{:?}` writes.
+    fn string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => { self.rest = &self.rest[i + 1..]; return Ok(s); }
+                '\\' => s.push(match chars.next().map(|(_, c)| c) {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('0') => '\0',
+                    Some(c @ ('\\' | '"' | '\'')) => c,
+                    Some('u') => {
+                        let digits: String = chars.by_ref().map(|(_, c)| c).skip(1).take_while(|&c| c != '}').collect();
+                        u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32).ok_or("bad \\u escape")?
+                    }
+                    _ => return Err(String::from("bad escape")),
+                }),
+                c => s.push(c),
             }
-        };
-        println!("{:04}: {:?} {:?}  a={}  b={}  prev_same={}",
-            i, ins.op, ins.ty, show_a(ins), show_b(ins), prev);
+        }
+        Err(String::from("unterminated string"))
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, prefix: &str) -> Result<T, String> {
+        let word = self.word()?;
+        word.strip_prefix(prefix).and_then(|n| n.parse().ok()).ok_or_else(|| format!("expected {prefix}number, got `{word}`"))
+    }
+
+    /// A ref below `below`: `rN`, or `-` for none.
+    fn operand_ref(&mut self, below: usize) -> Result<Ref, String> {
+        if self.eat("-") { return Ok(Ref::NONE); }
+        let r: u16 = self.number("r")?;
+        if r as usize >= below { return Err(format!("r{r} does not come before its use")); }
+        Ok(Ref(r))
+    }
+
+    fn refs(&mut self, below: usize) -> Result<Vec<Ref>, String> {
+        if !self.eat("[") { return Err(String::from("expected `[`")); }
+        let mut refs = Vec::new();
+        while !self.eat("]") {
+            if self.done() { return Err(String::from("expected `]`")); }
+            refs.push(self.operand_ref(below)?);
+        }
+        Ok(refs)
+    }
+}
+
+impl IR {
+    /// Reads IR written out by its `Display`, as hand-written tests of
+    /// the passes over it can be. Blank lines and `;` comments are skipped.
+    /// Constants are interned like the `emit_k*` functions would, and the
+    /// skip chains are rebuilt, so passes run on it like on a recorded IR.
+    pub fn parse(src: &str) -> Result<IR, IrParseError> {
+        let mut ir = IR::new();
+        for (n, line) in src.lines().enumerate() {
+            let mut c = Cursor { rest: line };
+            if c.done() { continue; }
+            ir.parse_line(&mut c).map_err(|msg| IrParseError { line: n + 1, msg })?;
+            if !c.done() { return Err(IrParseError { line: n + 1, msg: format!("unexpected `{}`", c.rest.trim()) }); }
+        }
+        Ok(ir)
     }
 
-    // Also show skip chains for a couple of ops
-    for &op in &[IROp::Add, IROp::StoreVar, IROp::LoadVar] {
-        let mut chain = Vec::new();
-        let mut cur = ir.last_of_op[op as usize];
-        while cur != u16::MAX {
-            chain.push(cur);
-            cur = ir.code[cur as usize].prev_same_op;
+    fn parse_line(&mut self, c: &mut Cursor) -> Result<(), String> {
+        let len = self.code.len();
+        if c.eat("snap ") {
+            let ins = c.number::<u16>("")?;
+            if ins as usize > len { return Err(format!("snapshot at {ins:04} past the last instruction")); }
+            if self.snapshots.last().is_some_and(|s| s.ins.0 > ins) { return Err(String::from("snapshots out of order")); }
+            let (func, pc) = (c.word()?.to_string(), c.number("pc=")?);
+            let stack = c.refs(len)?;
+            let mut callers = Vec::new();
+            while c.eat("caller ") {
+                let (func, pc) = (c.word()?.to_string(), c.number("pc=")?);
+                callers.push(SnapFrame { func, pc, stack: c.refs(len)? });
+            }
+            self.snapshots.push(Snapshot { ins: Ref(ins), func, pc, stack, callers });
+            return Ok(());
+        }
+        let index: usize = c.number("")?;
+        if index != len { return Err(format!("instruction {index:04} where {len:04} is next")); }
+        let name = c.word()?;
+        let op = *IROp::ALL.iter().find(|op| format!("{op:?}") == name).ok_or_else(|| format!("unknown op `{name}`"))?;
+        let name = c.word()?;
+        let ty = *IRType::ALL.iter().find(|ty| format!("{ty:?}") == name).ok_or_else(|| format!("unknown type `{name}`"))?;
+        let (ka, kb) = operands(op);
+        let mut refs = [Ref::NONE; 2];
+        for (kind, r) in [ka, kb].into_iter().zip(&mut refs) {
+            *r = match kind {
+                Operand::Unused => Ref::NONE,
+                Operand::Ref => c.operand_ref(len)?,
+                Operand::Sym => {
+                    let word = c.word()?;
+                    let sym = word.strip_prefix('@').ok_or_else(|| format!("expected @symbol, got `{word}`"))?;
+                    Ref(self.intern_sym(sym))
+                }
+                Operand::Imm => Ref(c.number("#")?),
+                Operand::Const => self.parse_const(c, op, ty)?,
+            };
         }
-        chain.reverse();
-        println!("chain({:?}): {:?}", op, chain);
+        self.push(IRIns { op, ty, a: refs[0], b: refs[1], prev_same_op: u16::MAX });
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Operand a of the constant instruction about to be pushed, adding it
+    /// to its pool and, unless it is there already, to the interning map.
+    fn parse_const(&mut self, c: &mut Cursor, op: IROp, ty: IRType) -> Result<Ref, String> {
+        let r = Ref(self.code.len() as u16);
+        let index = |n: usize| Ref(n as u16);
+        Ok(match op {
+            IROp::KInt => {
+                let n = c.number("#")?;
+                self.const_map.entry(n).or_insert(r);
+                self.const_pool.push(n);
+                index(self.const_pool.len() - 1)
+            }
+            IROp::KNum => {
+                let x: f64 = c.number("#")?;
+                self.knum_map.entry(x.to_bits()).or_insert(r);
+                self.knum_pool.push(x);
+                index(self.knum_pool.len() - 1)
+            }
+            IROp::KStr => {
+                if !c.eat("#\"") { return Err(String::from("expected a #\"string\"")); }
+                let s: Rc<str> = c.string()?.into();
+                self.kstr_map.entry(Rc::clone(&s)).or_insert(r);
+                self.kstr_pool.push(s);
+                index(self.kstr_pool.len() - 1)
+            }
+            _ => match (c.word()?, ty) {
+                ("#nil", IRType::Nil) => Ref(0),
+                ("#false", IRType::Bool) => Ref(0),
+                ("#true", IRType::Bool) => Ref(1),
+                (word, ty) => return Err(format!("`{word}` is no {ty:?} constant")),
+            },
+        })
+    }
+}
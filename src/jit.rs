@@ -1,29 +1,486 @@
-use crate::ir::{Ref, IR, IROp, IRIns, IRType};
+use crate::ir::{Ref, IR, IROp, IRIns, IRType, SnapFrame, Snapshot};
 use crate::bytecode::BC;
+use crate::native::Native;
+use crate::stdlib;
+use crate::table::Table;
+use crate::gc::GcRef;
+use crate::vm::{arith_add, compare, RuntimeError, Value, VM};
 
 
-use std::collections::HashMap;
-use std::u16;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
-struct Recorder {
+/* ================= JIT driver ================= */
+
+#[derive(Debug, Clone)]
+pub struct JitOptions {
+    pub enabled: bool,
+    /// Number of interpreted calls before a function is recorded.
+    pub hot_threshold: u32,
+    /// How many times a recursive call is inlined before the recorder turns
+    /// the recursion into a loop, or gives up.
+    pub max_unroll: usize,
+    /// `-jv`: log on stderr when a trace starts, stops or aborts, and why.
+    pub verbose: bool,
+    /// `-jdump`: print each trace on stderr as it is finished: the bytecode
+    /// recorded, the IR after each pass and the snapshots.
+    pub dump: bool,
+}
+
+impl Default for JitOptions {
+    fn default() -> Self {
+        Self { enabled: true, hot_threshold: 10, max_unroll: 2, verbose: false, dump: false }
+    }
+}
+
+/// A recorded function body, ready to run in place of the interpreter.
+/// Traces of a whole function start at pc 0; up-recursion traces start
+/// where a recursive call returns to, named `func@pc`.
+pub struct Trace {
+    pub name: String,
+    pub func: String,
+    pub pc: usize,
+    pub ir: IR,
+}
+
+/// How a trace run ended.
+pub enum TraceExit {
+    Return(Value),
+    /// A guard failed; the interpreter resumes these frames, outermost first.
+    Side(Vec<ExitFrame>),
+}
+
+/// A function to resume in the interpreter at `pc` with `stack`.
+pub struct ExitFrame {
+    pub func: String,
+    pub pc: usize,
+    pub stack: Vec<Value>,
+}
+
+// Deepest call nesting the recorder inlines.
+const MAX_INLINE_DEPTH: usize = 8;
+// Longest trace the recorder builds, since tail calls can chain without end.
+const MAX_TRACE_LEN: usize = 4000;
+
+impl Trace {
+    /// Runs the trace in the current VM frame; `stack` is the operand stack
+    /// an up-recursion trace starts with.
+    pub fn execute(&self, vm: &mut VM, stack: &[Value]) -> Result<TraceExit, RuntimeError> {
+        let depth = vm.frame_depth();
+        let ret = self.run(vm, stack);
+        // frames of inlined calls stay for a side exit to resume, not on errors
+        if ret.is_err() { vm.truncate_frames(depth); }
+        ret
+    }
+
+    fn run(&self, vm: &mut VM, stack: &[Value]) -> Result<TraceExit, RuntimeError> {
+        let ir = &self.ir;
+        // Every instruction gets a slot so refs index straight into `vals`.
+        let mut vals: Vec<Value> = Vec::with_capacity(ir.code.len());
+        // callers a down-recursive loop descended from, outermost first
+        let mut conts: Vec<ExitFrame> = Vec::new();
+        let mut i = 0;
+        while let Some(ins) = ir.code.get(i) {
+            let v = match ins.op {
+                IROp::KInt | IROp::KNum | IROp::KStr | IROp::KPri => ir.konst(Ref(i as u16)).unwrap(),
+                IROp::StrCat => vm.concat(&vals[ins.a.0 as usize], &vals[ins.b.0 as usize])?,
+                IROp::Eq | IROp::Ne | IROp::Lt | IROp::Le | IROp::Gt | IROp::Ge => {
+                    let op = ins.op.as_compare().unwrap();
+                    Value::Bool(compare(op, &vals[ins.a.0 as usize], &vals[ins.b.0 as usize])?)
+                }
+                IROp::Add => match (&vals[ins.a.0 as usize], &vals[ins.b.0 as usize], ins.ty) {
+                    (Value::Float(x), Value::Float(y), IRType::Float) => Value::Float(x + y),
+                    (x, y, _) => arith_add(x, y)?,
+                },
+                IROp::AddOv => {
+                    let (Value::Int(x), Value::Int(y)) = (&vals[ins.a.0 as usize], &vals[ins.b.0 as usize]) else {
+                        unreachable!("AddOv on non-int operands")
+                    };
+                    match x.checked_add(*y) {
+                        Some(n) => Value::Int(n),
+                        None => return Ok(self.exit(i, &vals, &mut conts)),
+                    }
+                }
+                IROp::Conv => Value::Float(vals[ins.a.0 as usize].as_float().unwrap()),
+                IROp::LoadVar => {
+                    let v = vm.get(ir.sym(ins.a.0));
+                    if IRType::of(&v) != ins.ty {
+                        return Ok(self.exit(i, &vals, &mut conts));
+                    }
+                    v
+                }
+                IROp::StoreVar => {
+                    vm.set(ir.sym(ins.a.0), vals[ins.b.0 as usize].clone());
+                    Value::Nil
+                }
+                IROp::Print => {
+                    println!("{}", vals[ins.a.0 as usize]);
+                    Value::Nil
+                }
+                IROp::Ret | IROp::RetN => {
+                    let v = if ins.op == IROp::Ret {
+                        let v = vals[ins.a.0 as usize].clone();
+                        vm.set_result(&v);
+                        v
+                    } else {
+                        let mut rets = Vec::new();
+                        collect_args(ir, &vals, ins.a, &mut rets);
+                        let v = rets.first().cloned().unwrap_or(Value::Nil);
+                        vm.set_results(rets);
+                        v
+                    };
+                    let Some(last) = conts.last_mut() else { return Ok(TraceExit::Return(v)) };
+                    // returning from a descent: the caller goes on in the interpreter
+                    vm.pop_frame();
+                    last.stack.push(v);
+                    return Ok(TraceExit::Side(conts));
+                }
+                IROp::SLoad => {
+                    let v = stack[ins.a.0 as usize].clone();
+                    if IRType::of(&v) != ins.ty {
+                        let entry = ExitFrame { func: self.func.clone(), pc: self.pc, stack: stack.to_vec() };
+                        return Ok(TraceExit::Side(vec![entry]));
+                    }
+                    v
+                }
+                IROp::Cont => {
+                    let snap = ir.snapshot_for(Ref(i as u16)).expect("Cont without snapshot");
+                    conts.extend(Self::snap_frames(snap, &vals));
+                    Value::Nil
+                }
+                IROp::FrameSwap => {
+                    let Value::Function(f) = vals[ins.a.0 as usize] else { unreachable!("FrameSwap on a non-function") };
+                    let frame = vm.closure_frame(f);
+                    vm.swap_frame(frame, ins.b.0 == 1);
+                    Value::Nil
+                }
+                IROp::Nop => Value::Nil,
+                IROp::Loop => {
+                    // the back-edge is a safe point; nothing in `vals` lives on
+                    vals.clear();
+                    vm.gc_check_with(stack.iter().chain(conts.iter().flat_map(|c| &c.stack)));
+                    i = 0;
+                    continue;
+                }
+                IROp::Abs => stdlib::num_abs(&vals[ins.a.0 as usize])?,
+                IROp::Min | IROp::Max | IROp::Pow => {
+                    let (x, y) = (&vals[ins.a.0 as usize], &vals[ins.b.0 as usize]);
+                    match ins.op {
+                        IROp::Min => stdlib::num_min(x, y)?,
+                        IROp::Max => stdlib::num_max(x, y)?,
+                        _ => stdlib::num_pow(x, y)?,
+                    }
+                }
+                IROp::Not => Value::Bool(!vals[ins.a.0 as usize].is_truthy()),
+                IROp::GuardTrue | IROp::GuardFalse => {
+                    if vals[ins.a.0 as usize].is_truthy() != (ins.op == IROp::GuardTrue) {
+                        return Ok(self.exit(i, &vals, &mut conts));
+                    }
+                    Value::Nil
+                }
+                IROp::TNew => vm.new_table(Table::with_capacity(ins.a.0 as usize, 0)),
+                IROp::ABC => {
+                    let (Value::Table(t), Value::Int(n)) = (&vals[ins.a.0 as usize], &vals[ins.b.0 as usize]) else {
+                        unreachable!("ABC on non-table or non-int")
+                    };
+                    if vm.heap().table(*t).array_get(*n).is_none() {
+                        return Ok(self.exit(i, &vals, &mut conts));
+                    }
+                    Value::Nil
+                }
+                IROp::ALoad | IROp::HLoad => {
+                    let slot = &ir.code[ins.a.0 as usize];
+                    let (t, k) = (&vals[slot.a.0 as usize], &vals[slot.b.0 as usize]);
+                    let v = match (ins.op, t, k) {
+                        // the ABC in front of this load keeps the index in bounds
+                        (IROp::ALoad, Value::Table(t), Value::Int(i)) => vm.heap().table(*t).array_get(*i).unwrap().clone(),
+                        _ => vm.index(t, k)?,
+                    };
+                    if IRType::of(&v) != ins.ty {
+                        return Ok(self.exit(i, &vals, &mut conts));
+                    }
+                    v
+                }
+                IROp::AStore | IROp::HStore => {
+                    let slot = &ir.code[ins.a.0 as usize];
+                    let (t, k) = (&vals[slot.a.0 as usize], &vals[slot.b.0 as usize]);
+                    let v = vals[ins.b.0 as usize].clone();
+                    match (ins.op, t, k) {
+                        (IROp::AStore, Value::Table(t), Value::Int(i)) => {
+                            vm.heap_mut().barrier(*t);
+                            vm.heap_mut().table_mut(*t).array_set(*i, v)
+                        }
+                        _ => vm.set_index(t, k, v)?,
+                    }
+                    Value::Nil
+                }
+                IROp::FNew => vm.new_closure(ir.sym(ins.a.0)),
+                IROp::GuardFn => {
+                    let same = match &vals[ins.a.0 as usize] {
+                        Value::Function(f) => vm.heap().closure(*f).proto.name == ir.sym(ins.b.0),
+                        _ => false,
+                    };
+                    if !same {
+                        return Ok(self.exit(i, &vals, &mut conts));
+                    }
+                    Value::Nil
+                }
+                IROp::FrameEnter => {
+                    let Value::Function(f) = vals[ins.a.0 as usize] else { unreachable!("FrameEnter on a non-function") };
+                    let frame = vm.closure_frame(f);
+                    vm.push_frame(frame)?;
+                    Value::Nil
+                }
+                IROp::FrameLeave => {
+                    vm.pop_frame();
+                    Value::Nil
+                }
+                IROp::ARef | IROp::HRef | IROp::CArg => Value::Nil,
+                IROp::CallN | IROp::CallS => {
+                    let mut args = Vec::new();
+                    collect_args(ir, &vals, ins.a, &mut args);
+                    // the native may collect, and only the trace holds `vals`
+                    let live = vals.iter().chain(stack).chain(conts.iter().flat_map(|c| &c.stack));
+                    vm.with_roots(live, |vm| vm.call_native(ir.sym(ins.b.0), &args))?
+                }
+            };
+            vals.push(v);
+            i += 1;
+        }
+        unreachable!("trace {} has no Ret", self.name)
+    }
+
+    /// Leaves the trace through the snapshot covering the guard at `at`.
+    fn exit(&self, at: usize, vals: &[Value], conts: &mut Vec<ExitFrame>) -> TraceExit {
+        let snap = self.ir.snapshot_for(Ref(at as u16)).expect("guard without snapshot");
+        let mut frames = std::mem::take(conts);
+        frames.extend(Self::snap_frames(snap, vals));
+        TraceExit::Side(frames)
+    }
+
+    fn snap_frames(snap: &Snapshot, vals: &[Value]) -> Vec<ExitFrame> {
+        let frame = |func: &str, pc, stack: &[Ref]| ExitFrame {
+            func: func.to_string(),
+            pc,
+            stack: stack.iter().map(|r| vals[r.0 as usize].clone()).collect(),
+        };
+        let mut frames: Vec<ExitFrame> = snap.callers.iter().map(|f| frame(&f.func, f.pc, &f.stack)).collect();
+        frames.push(frame(&snap.func, snap.pc, &snap.stack));
+        frames
+    }
+}
+
+/// Flattens a `CArg` chain back into the argument values it was built from.
+fn collect_args(ir: &IR, vals: &[Value], r: Ref, out: &mut Vec<Value>) {
+    if r == Ref::NONE { return; }
+    let ins = &ir.code[r.0 as usize];
+    if ins.op == IROp::CArg {
+        collect_args(ir, vals, ins.a, out);
+        out.push(vals[ins.b.0 as usize].clone());
+    } else {
+        out.push(vals[r.0 as usize].clone());
+    }
+}
+
+pub struct Jit {
+    pub opts: JitOptions,
+    hotcounts: HashMap<String, u32>,
+    traces: HashMap<String, Rc<Trace>>,
+    blacklist: HashSet<String>,
+}
+
+impl Jit {
+    pub fn new(opts: JitOptions) -> Self {
+        Self { opts, hotcounts: HashMap::new(), traces: HashMap::new(), blacklist: HashSet::new() }
+    }
+
+    pub fn trace(&self, name: &str) -> Option<Rc<Trace>> {
+        if !self.opts.enabled { return None; }
+        self.traces.get(name).cloned()
+    }
+
+    pub fn traces(&self) -> impl Iterator<Item = &Trace> {
+        self.traces.values().map(|t| t.as_ref())
+    }
+
+    /// Counts an interpreted call to `name`; true when it should be recorded.
+    pub(crate) fn tick(&mut self, name: &str) -> bool {
+        if !self.opts.enabled || self.blacklist.contains(name) { return false; }
+        let n = self.hotcounts.entry(name.to_string()).or_insert(0);
+        *n += 1;
+        *n >= self.opts.hot_threshold
+    }
+
+    /// `rec`, about to record, logged as started.
+    pub(crate) fn started(&self, mut rec: Recorder) -> Recorder {
+        if self.opts.verbose { eprintln!("[TRACE start {} pc={}]", rec.name, rec.pc); }
+        if self.opts.dump { rec.bytecode = Some(Vec::new()); }
+        rec
+    }
+
+    pub(crate) fn finish(&mut self, mut rec: Recorder) {
+        let recorded = self.opts.dump.then(|| (rec.bytecode.take().unwrap_or_default(), rec.ir.to_string()));
+        let trace = rec.finish();
+        if let Some((bytecode, ir)) = recorded { dump(&trace, &bytecode, &ir); }
+        if self.opts.verbose {
+            eprintln!("[TRACE stop {} pc={} -> {}, {} IR]", trace.name, trace.pc, ending(&trace.ir), trace.ir.code.len());
+        }
+        self.traces.insert(trace.name.clone(), Rc::new(trace));
+    }
+
+    /// Drops an unfinished recording; the function is not tried again.
+    pub(crate) fn abort(&mut self, rec: Recorder, reason: String) {
+        if let Some(bytecode) = &rec.bytecode {
+            eprintln!("---- TRACE {} bytecode, aborted", rec.name);
+            for line in bytecode { eprintln!("{line}"); }
+        }
+        if self.opts.verbose { eprintln!("[TRACE abort {} pc={} -- {reason}]", rec.name, rec.pc); }
+        self.blacklist.insert(rec.name);
+    }
+}
+
+/// How a trace ends: looping back to its start, or returning.
+fn ending(ir: &IR) -> &'static str {
+    match ir.code.iter().rev().find(|ins| ins.op != IROp::Nop).map(|ins| ins.op) {
+        Some(IROp::Loop) => "loop",
+        _ => "return",
+    }
+}
+
+/// The `-jdump` listing of a finished trace, given the bytecode recorded
+/// and the IR as recorded, folded and CSEd as it went, in textual form.
+/// There is no machine code to show: traces run on the IR.
+fn dump(trace: &Trace, bytecode: &[String], recorded: &str) {
+    let name = &trace.name;
+    eprintln!("---- TRACE {name} start {} pc={}", trace.func, trace.pc);
+    eprintln!("---- TRACE {name} bytecode");
+    for line in bytecode { eprintln!("{line}"); }
+    eprintln!("---- TRACE {name} IR recorded");
+    for line in recorded.lines().filter(|l| !l.starts_with("snap")) { eprintln!("{line}"); }
+    let text = trace.ir.to_string();
+    let (code, snapshots): (Vec<&str>, Vec<&str>) = text.lines().partition(|l| !l.starts_with("snap"));
+    eprintln!("---- TRACE {name} IR after DCE");
+    for line in code { eprintln!("{line}"); }
+    eprintln!("---- TRACE {name} snapshots");
+    for line in snapshots { eprintln!("{line}"); }
+    eprintln!("---- TRACE {name} mcode");
+    eprintln!("(none: traces are run by the IR interpreter)");
+    eprintln!("---- TRACE {name} stop -> {}", ending(&trace.ir));
+}
+
+/* ================= Recorder ================= */
+
+pub(crate) struct Recorder {
+    name: String,
     ir: IR,
     stack: Vec<Ref>,
-    env: HashMap<u16, Ref>
+    env: HashMap<u16, Ref>,
+    // function being recorded, `name` or a call inlined into it
+    func: String,
+    // function the trace starts in
+    root: String,
+    // callers of the inlined call being recorded, outermost first
+    frames: Vec<RecFrame>,
+    // where the trace starts in the traced function
+    pc: usize,
+    // results of the call recorded last, unknown for one made before the trace
+    rets: Option<Vec<Ref>>,
+    // whether the call being recorded returns only its first result, having
+    // been taken over by a tail call followed by a Ret
+    first_only: bool,
+    // length of the open list on top of `stack`
+    open: Option<usize>,
+    max_unroll: usize,
+    done: bool,
+    // the bytecode recorded, as `-jdump` lists it, when dumping
+    bytecode: Option<Vec<String>>,
+}
+
+/// Recorder state of a caller while a call it made is recorded inline.
+struct RecFrame {
+    func: String,
+    // pc after the call
+    pc: usize,
+    stack: Vec<Ref>,
+    first_only: bool,
 }
 
 impl Recorder{
-    pub fn new() -> Self {
-        Self { ir: IR::new(), stack: Vec::new(), env: HashMap::new() }
+    pub fn new(name: &str, max_unroll: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            ir: IR::new(),
+            stack: Vec::new(),
+            env: HashMap::new(),
+            func: name.to_string(),
+            root: name.to_string(),
+            frames: Vec::new(),
+            pc: 0,
+            rets: None,
+            first_only: false,
+            open: None,
+            max_unroll,
+            done: false,
+            bytecode: None,
+        }
+    }
+
+    /// Recorder for the up-recursion trace `name`: `func` resuming at `pc`
+    /// with `stack`, as a recursive call returns into it.
+    pub fn new_up(name: &str, func: &str, pc: usize, stack: &[Value], max_unroll: usize) -> Self {
+        let mut rec = Self { func: func.to_string(), root: func.to_string(), pc, ..Self::new(name, max_unroll) };
+        for (slot, v) in stack.iter().enumerate() {
+            let r = rec.ir.push(IRIns { op: IROp::SLoad, ty: IRType::of(v), a: Ref(slot as u16), b: Ref::NONE, prev_same_op: u16::MAX });
+            rec.stack.push(r);
+        }
+        rec
+    }
+
+    /// Whether the traced function has returned, completing the trace.
+    pub fn done(&self) -> bool { self.done }
+
+    pub fn finish(self) -> Trace {
+        let mut ir = self.ir;
+        ir.dce();
+        Trace { name: self.name, func: self.root, pc: self.pc, ir }
     }
 
-    fn emit_add(&mut self, a: Ref, b: Ref) -> Ref {
-        // constant folding, if we're adding two const combine them into one
-        if let (Some(x), Some(y)) = (self.ir.const_value(a), self.ir.const_value(b)) {
-            return self.ir.emit_kint(x + y);
+    fn ty(&self, r: Ref) -> IRType { self.ir.code[r.0 as usize].ty }
+
+    /// Result type of arithmetic on `args`: int if all are ints, float once
+    /// a float is involved, and unspecialised if any type is unknown.
+    fn arith_type(&self, args: &[Ref]) -> IRType {
+        let tys: Vec<IRType> = args.iter().map(|&r| self.ty(r)).collect();
+        if tys.contains(&IRType::Any) { IRType::Any }
+        else if tys.iter().all(|&t| t == IRType::Int) { IRType::Int }
+        else { IRType::Float }
+    }
+
+    /// `r` as a float operand, converting ints.
+    fn emit_tonum(&mut self, r: Ref) -> Ref {
+        if self.ty(r) != IRType::Int { return r; }
+        match self.ir.const_value(r) {
+            Some(n) => self.ir.emit_knum(n as f64),
+            None => self.emit_pure(IROp::Conv, IRType::Float, r, Ref::NONE),
+        }
+    }
+
+    /// Emits `a + b` for the Add at `pc`, whose operands are still on the stack.
+    fn emit_add(&mut self, a: Ref, b: Ref, pc: usize) -> Ref {
+        // constant folding, if we're adding two const combine them into one;
+        // arith_add gives the interpreter's result, overflow included
+        if let (Some(x), Some(y)) = (self.ir.konst(a), self.ir.konst(b))
+            && let Ok(v) = arith_add(&x, &y) {
+            return self.emit_konst(&v);
         }
 
+        let ty = self.arith_type(&[a, b]);
+        let (a, b) = if ty == IRType::Float { (self.emit_tonum(a), self.emit_tonum(b)) } else { (a, b) };
+        // int adds must exit to the interpreter when they overflow
+        let op = if ty == IRType::Int { IROp::AddOv } else { IROp::Add };
+
         // common subexpression elimination (CSE) using skip chain lookback
-        let mut prev = self.ir.last_of_op[IROp::Add as usize];
+        let mut prev = self.ir.last_of_op[op as usize];
         while prev != u16::MAX {
             let candidate = &self.ir.code[prev as usize];
             let same = (candidate.a == a && candidate.b == b) || (candidate.a == b && candidate.b == a);
@@ -32,21 +489,234 @@ impl Recorder{
             prev = candidate.prev_same_op;
         }
 
+        if op == IROp::AddOv { self.snapshot(pc); }
         self.ir.push(IRIns {
-                op: IROp::Add,
-                ty: IRType::Int,
+                op,
+                ty,
                 a,
                 b,
                 prev_same_op: u16::MAX,
         })
     }
 
-    fn emit_loadvar(&mut self, sym: u16) -> Ref {
+    /// Finds an earlier `op` with the same operands by walking its skip chain.
+    fn cse(&self, op: IROp, a: Ref, b: Ref) -> Option<Ref> {
+        let mut prev = self.ir.last_of_op[op as usize];
+        while prev != u16::MAX {
+            let candidate = &self.ir.code[prev as usize];
+            if candidate.a == a && candidate.b == b { return Some(Ref(prev)); }
+            prev = candidate.prev_same_op;
+        }
+        None
+    }
+
+    fn emit_pure(&mut self, op: IROp, ty: IRType, a: Ref, b: Ref) -> Ref {
+        if let Some(r) = self.cse(op, a, b) { return r; }
+        self.ir.push(IRIns { op, ty, a, b, prev_same_op: u16::MAX })
+    }
+
+    fn emit_call(&mut self, native: &Native, args: &[Ref], vm: &mut VM) -> Ref {
+        if native.pure {
+            // constant folding: a pure call on constants can run right now
+            let consts: Option<Vec<Value>> = args.iter().map(|&r| self.ir.konst(r)).collect();
+            if let Some(consts) = consts
+                && let Ok(v) = (native.f)(vm, &consts) {
+                return self.emit_konst(&v);
+            }
+        }
+
+        // intrinsics inline as a single instruction instead of a call
+        if let Some(op) = native.intrinsic {
+            let b = args.get(1).copied().unwrap_or(Ref::NONE);
+            // pow and abs of ints turn into floats when the int result overflows
+            let ty = match (op, self.arith_type(args)) {
+                (IROp::Pow | IROp::Abs, IRType::Int) => IRType::Any,
+                (_, ty) => ty,
+            };
+            return self.emit_pure(op, ty, args[0], b);
+        }
+
+        let list = self.emit_list(args);
+        let sym = Ref(self.ir.intern_sym(&native.name));
+        if native.pure {
+            self.emit_pure(IROp::CallN, IRType::Any, list, sym)
+        } else {
+            // the callee may have changed any variable behind our back
+            self.env.clear();
+            self.ir.push(IRIns {
+                op: IROp::CallS,
+                ty: IRType::Any,
+                a: list,
+                b: sym,
+                prev_same_op: u16::MAX
+            })
+        }
+    }
+
+    /// Left-nested CArg list of `vals`; a single value stands alone.
+    fn emit_list(&mut self, vals: &[Ref]) -> Ref {
+        let mut list = Ref::NONE;
+        for &v in vals {
+            list = if list == Ref::NONE { v } else { self.emit_pure(IROp::CArg, IRType::Any, list, v) };
+        }
+        list
+    }
+
+    fn emit_konst(&mut self, v: &Value) -> Ref {
+        match v {
+            Value::Int(n) => self.ir.emit_kint(*n),
+            Value::Float(x) => self.ir.emit_knum(*x),
+            Value::Str(s) => self.ir.emit_kstr(Rc::clone(s)),
+            _ => self.ir.emit_kpri(v),
+        }
+    }
+
+    /// Truthiness of `r` if its type alone decides it.
+    fn truthiness(&self, r: Ref) -> Option<bool> {
+        match self.ir.konst(r) {
+            Some(v) => Some(v.is_truthy()),
+            None => match self.ir.code[r.0 as usize].ty {
+                IRType::Int | IRType::Float | IRType::Str | IRType::Table | IRType::Function => Some(true),
+                IRType::Nil => Some(false),
+                IRType::Bool | IRType::Any => None,
+            },
+        }
+    }
+
+    /// Snapshots the interpreter state at `pc` for the guards emitted next.
+    fn snapshot(&mut self, pc: usize) {
+        let snap = Snapshot {
+            ins: Ref(self.ir.code.len() as u16),
+            func: self.func.clone(),
+            pc,
+            stack: self.stack.clone(),
+            callers: self.frames.iter().map(|f| SnapFrame { func: f.func.clone(), pc: f.pc, stack: f.stack.clone() }).collect(),
+        };
+        // a snapshot nothing was emitted under is dead, replace it
+        match self.ir.snapshots.last_mut() {
+            Some(last) if last.ins == snap.ins => *last = snap,
+            _ => self.ir.snapshots.push(snap),
+        }
+    }
+
+    fn emit_guard(&mut self, r: Ref, truthy: bool, pc: usize) {
+        self.snapshot(pc);
+        self.ir.push(IRIns {
+            op: if truthy { IROp::GuardTrue } else { IROp::GuardFalse },
+            ty: IRType::Any,
+            a: r,
+            b: Ref::NONE,
+            prev_same_op: u16::MAX
+        });
+    }
+
+    /// Address of `t[k]` for the GetIndex/SetIndex at `pc`, whose operands
+    /// are still on the stack. Int keys that hit the array part get an ARef
+    /// behind a bounds check, anything else a generic HRef.
+    fn emit_slot(&mut self, t: Ref, k: Ref, key: &Value, tab: &Value, pc: usize, vm: &VM) -> Result<Ref, String> {
+        if self.ty(t) != IRType::Table {
+            return Err(String::from("NYI: indexing a value not known to be a table"));
+        }
+        self.snapshot(pc);
+        let in_array = match (key, tab) {
+            (Value::Int(i), Value::Table(tab)) => vm.heap().table(*tab).array_get(*i).is_some(),
+            _ => false,
+        };
+        if self.ty(k) == IRType::Int && in_array {
+            // never CSE'd: a store in between may have shrunk the array
+            self.ir.push(IRIns { op: IROp::ABC, ty: IRType::Any, a: t, b: k, prev_same_op: u16::MAX });
+            Ok(self.emit_pure(IROp::ARef, IRType::Any, t, k))
+        } else {
+            Ok(self.emit_pure(IROp::HRef, IRType::Any, t, k))
+        }
+    }
+
+    /// Inlines the call at `pc` in `code` to the closure `callee`, held in `f`:
+    /// guards that `f` stays a closure over the same prototype, then enters
+    /// a frame for the call and records its body next. A tail call swaps the
+    /// callee's frame in for the caller's instead, passing on only the first
+    /// result when a Ret follows it.
+    ///
+    /// Recursive calls are unrolled up to `max_unroll` times. Past that, or
+    /// straight away for a tail call, recursion of the traced function
+    /// closes the trace into a loop back to its start; other recursion
+    /// aborts.
+    fn emit_inline(&mut self, f: Ref, callee: GcRef, code: &[BC], argc: usize, pc: usize, vm: &VM) -> Result<(), String> {
+        let op = &code[pc];
+        let on_stack = matches!(op, BC::CallValue(_) | BC::TailCallValue(_));
+        let tail = matches!(op, BC::TailCall(..) | BC::TailCallValue(_));
+        let proto = Rc::clone(&vm.heap().closure(callee).proto);
+        if proto.variadic {
+            return Err(format!("NYI: call to variadic {}", proto.name));
+        }
+        if proto.params.len() != argc {
+            return Err(format!("arity mismatch for {}", proto.name));
+        }
+        // activations of the callee the recording is already in
+        let level = self.frames.iter().filter(|fr| fr.func == proto.name).count() + usize::from(self.func == proto.name);
+        // only a trace of a whole function can loop back to its start
+        let looping = self.root == proto.name && self.pc == 0;
+        let tail_loop = tail && looping && self.frames.is_empty();
+        if !tail && level > self.max_unroll && !looping {
+            return Err(format!("NYI: recursion of {} deeper than the unroll limit", proto.name));
+        }
+        if !tail && level <= self.max_unroll && self.frames.len() >= MAX_INLINE_DEPTH {
+            return Err(String::from("inlining too deep"));
+        }
+        let sym = self.ir.intern_sym(&proto.name);
+        self.snapshot(pc);
+        self.ir.push(IRIns { op: IROp::GuardFn, ty: IRType::Any, a: f, b: Ref(sym), prev_same_op: u16::MAX });
+
+        let args = self.stack.split_off(self.stack.len() - argc);
+        if on_stack { self.stack.pop(); }
+        if tail {
+            // the callee's frame replaces this one; tail recursion of the
+            // traced function reruns the trace in it
+            let first_only = matches!(code.get(pc + 1), Some(BC::Ret));
+            self.ir.push(IRIns { op: IROp::FrameSwap, ty: IRType::Any, a: f, b: Ref(u16::from(first_only)), prev_same_op: u16::MAX });
+            self.first_only |= first_only;
+            self.func = proto.name.clone();
+            self.stack.clear();
+        } else if level > self.max_unroll {
+            // down-recursion: the callers wait for the result while the
+            // trace reruns for the call
+            self.snapshot(pc + 1);
+            self.ir.push(IRIns { op: IROp::Cont, ty: IRType::Any, a: Ref::NONE, b: Ref::NONE, prev_same_op: u16::MAX });
+            self.ir.push(IRIns { op: IROp::FrameEnter, ty: IRType::Any, a: f, b: Ref::NONE, prev_same_op: u16::MAX });
+        } else {
+            self.ir.push(IRIns { op: IROp::FrameEnter, ty: IRType::Any, a: f, b: Ref::NONE, prev_same_op: u16::MAX });
+            self.frames.push(RecFrame {
+                func: std::mem::replace(&mut self.func, proto.name.clone()),
+                pc: pc + 1,
+                stack: std::mem::take(&mut self.stack),
+                first_only: std::mem::take(&mut self.first_only),
+            });
+        }
+        self.env.clear();
+        for (p, a) in proto.params.iter().zip(args) {
+            let sym = self.ir.intern_sym(p);
+            self.emit_storevar(sym, a);
+        }
+        if tail_loop || (!tail && level > self.max_unroll) {
+            self.ir.push(IRIns { op: IROp::Loop, ty: IRType::Any, a: Ref::NONE, b: Ref::NONE, prev_same_op: u16::MAX });
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    fn emit_store(&mut self, op: IROp, slot: Ref, v: Ref) {
+        self.ir.push(IRIns { op, ty: IRType::Any, a: slot, b: v, prev_same_op: u16::MAX });
+    }
+
+    /// Loads a variable specialised to the type it has now, guarded
+    /// against it having another type on later runs.
+    fn emit_loadvar(&mut self, sym: u16, ty: IRType, pc: usize) -> Ref {
         if let Some(&r) = self.env.get(&sym){ return r; };
 
+        self.snapshot(pc);
         let r = self.ir.push(IRIns {
             op : IROp::LoadVar,
-            ty : IRType::Int,
+            ty,
             a: Ref(sym),
             b: Ref::NONE,
             prev_same_op: u16::MAX
@@ -58,11 +728,11 @@ impl Recorder{
 
     fn emit_storevar(&mut self, sym: u16, v: Ref) {
         if self.env.get(&sym).copied() == Some(v) { return; }
-        self.ir.push(IRIns { 
+        self.ir.push(IRIns {
             op: IROp::StoreVar,
             ty: IRType::Any,
             a: Ref(sym),
-            b: v, 
+            b: v,
             prev_same_op: u16::MAX });
         self.env.insert(sym, v);
     }
@@ -77,40 +747,278 @@ impl Recorder{
         });
     }
 
-    pub fn record(&mut self, bc: Vec<BC>){
-        for op in bc {
-            match op {
-                BC::LoadConst(n) => {
-                    let r = self.ir.emit_kint(n);
-                    self.stack.push(r);
+    fn emit_ret(&mut self, v: Ref) {
+        self.ir.push(IRIns {
+            op: IROp::Ret,
+            ty: IRType::Any,
+            a: v,
+            b: Ref::NONE,
+            prev_same_op: u16::MAX
+        });
+    }
+
+    /// Records the bytecode at `pc` in `code` ahead of the interpreter executing it,
+    /// reading the operands it needs off the interpreter's stack in `vm`.
+    /// `Err` carries the reason the trace has to be aborted.
+    pub fn record(&mut self, code: &[BC], pc: usize, vm: &mut VM) -> Result<(), String> {
+        let op = &code[pc];
+        if let Some(log) = &mut self.bytecode {
+            // inlined calls are indented a dot per level
+            let op = format!("{}{op}", ". ".repeat(self.frames.len()));
+            log.push(format!("{pc:04} {op:<32} ; {}", self.func));
+        }
+        if self.ir.code.len() > MAX_TRACE_LEN {
+            return Err(String::from("trace too long"));
+        }
+        match op {
+            BC::LoadConst(n) => {
+                let r = self.ir.emit_kint(*n);
+                self.stack.push(r);
+            }
+            BC::LoadFloat(x) => {
+                let r = self.ir.emit_knum(*x);
+                self.stack.push(r);
+            }
+            BC::LoadStr(s) => {
+                let r = self.ir.emit_kstr(vm.intern(s));
+                self.stack.push(r);
+            }
+            BC::LoadBool(b) => {
+                let r = self.ir.emit_kpri(&Value::Bool(*b));
+                self.stack.push(r);
+            }
+            BC::LoadNil => {
+                let r = self.ir.emit_kpri(&Value::Nil);
+                self.stack.push(r);
+            }
+            BC::LoadVar(name) => {
+                let sym = self.ir.intern_sym(name.as_str());
+                let ty = IRType::of(&vm.get(name));
+                let r = self.emit_loadvar(sym, ty, pc);
+                self.stack.push(r);
+            }
+            // both loads are emitted before either is pushed, so that a
+            // guard of the second exits to a state that redoes the pair
+            BC::LoadVarLoadVar(a, b) => {
+                let mut load = |name: &str| {
+                    let sym = self.ir.intern_sym(name);
+                    let ty = IRType::of(&vm.get(name));
+                    self.emit_loadvar(sym, ty, pc)
+                };
+                let (a, b) = (load(a), load(b));
+                self.stack.extend([a, b]);
+            }
+            // the operand stays on the stack until the add is emitted, for
+            // its overflow exit to redo the AddConst
+            BC::AddConst(n) => {
+                let a = *self.stack.last().expect("stack underflow");
+                if !matches!(self.ty(a), IRType::Int | IRType::Float | IRType::Any) {
+                    return Err(String::from("arithmetic on a non-number"));
                 }
-                BC::LoadVar(name) => {
-                    let sym = self.ir.intern_sym(name.as_str());
-                    let r = self.emit_loadvar(sym);
-                    self.stack.push(r);
+                let k = self.ir.emit_kint(*n);
+                let r = self.emit_add(a, k, pc);
+                self.stack.pop();
+                self.stack.push(r);
+            }
+            BC::Add => {
+                let n = self.stack.len();
+                let (a, b) = (self.stack[n - 2], self.stack[n - 1]);
+                for r in [a, b] {
+                    if !matches!(self.ty(r), IRType::Int | IRType::Float | IRType::Any) {
+                        return Err(String::from("arithmetic on a non-number"));
+                    }
                 }
-                BC::Add => {
-                    let b = self.stack.pop().expect("stack underflow");
-                    let a = self.stack.pop().expect("stack underflow");
-                    let r = self.emit_add(a, b);
-                    self.stack.push(r);
+                let r = self.emit_add(a, b, pc);
+                self.stack.truncate(n - 2);
+                self.stack.push(r);
+            }
+            BC::Concat => {
+                let b = self.stack.pop().expect("stack underflow");
+                let a = self.stack.pop().expect("stack underflow");
+                let r = match (self.ir.konst(a), self.ir.konst(b)) {
+                    (Some(x), Some(y)) => match vm.concat(&x, &y) {
+                        Ok(v) => self.emit_konst(&v),
+                        Err(_) => return Err(String::from("bad concatenation")),
+                    },
+                    _ => self.emit_pure(IROp::StrCat, IRType::Str, a, b),
+                };
+                self.stack.push(r);
+            }
+            BC::Compare(op) => {
+                let b = self.stack.pop().expect("stack underflow");
+                let a = self.stack.pop().expect("stack underflow");
+                let r = match (self.ir.konst(a), self.ir.konst(b)) {
+                    (Some(x), Some(y)) => match compare(*op, &x, &y) {
+                        Ok(t) => self.ir.emit_kpri(&Value::Bool(t)),
+                        Err(_) => return Err(String::from("bad comparison")),
+                    },
+                    _ => self.emit_pure(IROp::compare(*op), IRType::Bool, a, b),
+                };
+                self.stack.push(r);
+            }
+            BC::NewTable(n) => {
+                let items = self.open.take().unwrap_or(0);
+                let items = self.stack.split_off(self.stack.len() - items);
+                let entries = self.stack.split_off(self.stack.len() - 2 * n);
+                let t = self.ir.push(IRIns {
+                    op: IROp::TNew,
+                    ty: IRType::Table,
+                    a: Ref(*n as u16),
+                    b: Ref::NONE,
+                    prev_same_op: u16::MAX
+                });
+                for kv in entries.chunks(2) {
+                    let slot = self.emit_pure(IROp::HRef, IRType::Any, t, kv[0]);
+                    self.emit_store(IROp::HStore, slot, kv[1]);
+                }
+                for (i, v) in items.into_iter().enumerate() {
+                    let k = self.ir.emit_kint((n + i + 1) as i64);
+                    let slot = self.emit_pure(IROp::HRef, IRType::Any, t, k);
+                    self.emit_store(IROp::HStore, slot, v);
+                }
+                self.stack.push(t);
+            }
+            BC::GetIndex => {
+                let (tab, key) = (vm.peek(1).clone(), vm.peek(0).clone());
+                let ty = IRType::of(&vm.index(&tab, &key).map_err(|e| e.to_string())?);
+                let n = self.stack.len();
+                let slot = self.emit_slot(self.stack[n - 2], self.stack[n - 1], &key, &tab, pc, vm)?;
+                let op = if self.ir.code[slot.0 as usize].op == IROp::ARef { IROp::ALoad } else { IROp::HLoad };
+                let r = self.ir.push(IRIns { op, ty, a: slot, b: Ref::NONE, prev_same_op: u16::MAX });
+                self.stack.truncate(n - 2);
+                self.stack.push(r);
+            }
+            BC::SetIndex => {
+                let (tab, key) = (vm.peek(2).clone(), vm.peek(1).clone());
+                let m = self.stack.len();
+                let slot = self.emit_slot(self.stack[m - 3], self.stack[m - 2], &key, &tab, pc, vm)?;
+                let v = self.stack.pop().expect("stack underflow");
+                let op = if self.ir.code[slot.0 as usize].op == IROp::ARef { IROp::AStore } else { IROp::HStore };
+                self.emit_store(op, slot, v);
+                let n = self.stack.len();
+                self.stack.truncate(n - 2);
+            }
+            BC::Not => {
+                let a = self.stack.pop().expect("stack underflow");
+                let r = match self.truthiness(a) {
+                    Some(t) => self.ir.emit_kpri(&Value::Bool(!t)),
+                    None => self.emit_pure(IROp::Not, IRType::Bool, a, Ref::NONE),
+                };
+                self.stack.push(r);
+            }
+            BC::JumpIfFalseOrPop(_) | BC::JumpIfTrueOrPop(_) => {
+                // follow the branch the interpreter is about to take
+                let r = *self.stack.last().expect("stack underflow");
+                let truthy = vm.peek(0).is_truthy();
+                if self.truthiness(r).is_none() {
+                    self.emit_guard(r, truthy, pc);
+                }
+                if truthy != matches!(op, BC::JumpIfTrueOrPop(_)) {
+                    self.stack.pop();
                 }
-                BC::StoreVar(name) => {
-                    let v = self.stack.pop().expect("stack underflow");
-                    let sym = self.ir.intern_sym(name.as_str());
-                    self.emit_storevar(sym, v);
+            }
+            BC::StoreVar(name) => {
+                let v = self.stack.pop().expect("stack underflow");
+                let sym = self.ir.intern_sym(name.as_str());
+                self.emit_storevar(sym, v);
+            }
+            BC::StoreLoad(name) => {
+                let v = *self.stack.last().expect("stack underflow");
+                let sym = self.ir.intern_sym(name.as_str());
+                self.emit_storevar(sym, v);
+            }
+            BC::Print => {
+                let v = self.stack.pop().expect("stack underflow");
+                self.emit_print(v);
+            }
+            // whatever computed the value only for it is left to DCE
+            BC::Pop => { self.stack.pop(); }
+            BC::Closure(name) => {
+                let sym = self.ir.intern_sym(name);
+                let r = self.ir.push(IRIns { op: IROp::FNew, ty: IRType::Function, a: Ref(sym), b: Ref::NONE, prev_same_op: u16::MAX });
+                self.stack.push(r);
+            }
+            BC::CallValue(argc) | BC::TailCallValue(argc) => {
+                let argc = argc + self.open.take().unwrap_or(0);
+                let f = self.stack[self.stack.len() - argc - 1];
+                let Value::Function(callee) = *vm.peek(argc) else {
+                    return Err(String::from("call of a non-function"));
+                };
+                if self.ty(f) != IRType::Function {
+                    return Err(String::from("NYI: call of a value not known to be a function"));
                 }
-                BC::Print => {
-                    let v = self.stack.pop().expect("stack underflow");
-                    self.emit_print(v);
+                self.emit_inline(f, callee, code, argc, pc, vm)?;
+            }
+            // a tail call to a native just records as a call, the Ret after it follows
+            BC::Call(name, argc) | BC::TailCall(name, argc) => {
+                let argc = argc + self.open.take().unwrap_or(0);
+                if let Value::Function(callee) = vm.get(name) {
+                    let sym = self.ir.intern_sym(name);
+                    let f = self.emit_loadvar(sym, IRType::Function, pc);
+                    return self.emit_inline(f, callee, code, argc, pc, vm);
                 }
-                BC::Call(name, n_args) => {
-                    /// >>????
+                if vm.module().funs.contains_key(name) {
+                    return Err(format!("NYI: call to {name}"));
                 }
-                BC::Ret => {
-                    //// >>>>>?????
+                let Some(native) = vm.natives().get(name).cloned() else {
+                    return Err(format!("undefined function {name}"));
+                };
+                if native.arity.is_some_and(|n| n != argc) {
+                    return Err(format!("arity mismatch for {name}"));
+                }
+                let args = self.stack.split_off(self.stack.len() - argc);
+                let r = self.emit_call(&native, &args, vm);
+                self.rets = Some(vec![r]);
+                self.stack.push(r);
+            }
+            BC::Results(want) => {
+                let Some(rets) = self.rets.clone() else {
+                    return Err(String::from("NYI: results of a call made before the trace"));
+                };
+                // the call pushed its first result already
+                self.stack.pop();
+                let n = want.unwrap_or(rets.len());
+                for i in 0..n {
+                    let r = match rets.get(i) { Some(&r) => r, None => self.ir.emit_kpri(&Value::Nil) };
+                    self.stack.push(r);
+                }
+                if want.is_none() { self.open = Some(n); }
+            }
+            BC::Varargs(_) => return Err(String::from("NYI: varargs")),
+            BC::Ret | BC::RetN(_) => {
+                let mut rets = match op {
+                    BC::RetN(n) => {
+                        let n = n + self.open.take().unwrap_or(0);
+                        self.stack.split_off(self.stack.len() - n)
+                    }
+                    _ => vec![match self.stack.pop() { Some(v) => v, None => self.ir.emit_kpri(&Value::Nil) }],
+                };
+                if self.first_only { rets.truncate(1); }
+                let v = match rets.first() { Some(&v) => v, None => self.ir.emit_kpri(&Value::Nil) };
+                match self.frames.pop() {
+                    // an inlined call returns into its caller's recording
+                    Some(caller) => {
+                        self.ir.push(IRIns { op: IROp::FrameLeave, ty: IRType::Any, a: Ref::NONE, b: Ref::NONE, prev_same_op: u16::MAX });
+                        self.func = caller.func;
+                        self.stack = caller.stack;
+                        self.first_only = caller.first_only;
+                        // the callee may have assigned variables it shares with the caller
+                        self.env.clear();
+                        self.stack.push(v);
+                        self.rets = Some(rets);
+                    }
+                    None if matches!(op, BC::Ret) => {
+                        self.emit_ret(v);
+                        self.done = true;
+                    }
+                    None => {
+                        let list = self.emit_list(&rets);
+                        self.ir.push(IRIns { op: IROp::RetN, ty: IRType::Any, a: list, b: Ref::NONE, prev_same_op: u16::MAX });
+                        self.done = true;
+                    }
                 }
             }
         }
+        Ok(())
     }
 }
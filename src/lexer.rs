@@ -1,58 +1,250 @@
 /* ================= Lexer ================= */
 
-use std::iter::Peekable;
-
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Number(i64),
+    Float(f64),
+    Str(String),
     Ident(String),
-    Plus, Assign, Semicolon, Comma,
-    LParen, RParen, LBrace, RBrace,
-    Print, Fn, Return,
+    Plus, Assign, Semicolon, Comma, Dot, DotDot, Ellipsis,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    LParen, RParen, LBrace, RBrace, LBracket, RBracket,
+    Print, Fn, Return, Let,
+    True, False, Nil, And, Or, Not,
+    /// Text that is no token: an unknown character, a malformed or out of
+    /// range literal, an unterminated string or comment. Lexing goes on after it.
+    Error(String),
     EOF,
 }
 
-pub struct Lexer<'a> { it: Peekable<std::str::Chars<'a>>, line_idx: usize }
+/// Where a token is: its byte range in the source and the 1-based line it starts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+}
+
+/// Tokens of a source string, one `next_token` at a time or as an iterator
+/// of `(Token, Span)` that ends before EOF.
+///
+/// Whitespace, `// line` and `/* block */` comments separate tokens. Integers
+/// are decimal, `0x` hex or `0b` binary, and `_` may separate digits anywhere
+/// in a number.
+#[derive(Clone)]
+pub struct Lexer<'a> { src: &'a str, pos: usize, line_idx: usize, span: Span, trivia_start: usize }
 impl<'a> Lexer<'a> {
-    pub fn new(s: &'a str) -> Self { Self { it: s.chars().peekable(), line_idx: 0usize } }
+    pub fn new(s: &'a str) -> Self { Self { src: s, pos: 0, line_idx: 0usize, span: Span::default(), trivia_start: 0 } }
+    /// 1-based line of the next unread character.
+    pub fn line(&self) -> usize { self.line_idx + 1 }
+    /// Span of the token `next_token` returned last.
+    pub fn span(&self) -> Span { self.span }
+    /// Source text of the token `next_token` returned last.
+    pub fn text(&self) -> &'a str { &self.src[self.span.start..self.span.end] }
+    /// The whitespace and comments between that token and the one before it.
+    pub fn trivia(&self) -> &'a str { &self.src[self.trivia_start..self.span.start] }
     pub fn next_token(&mut self) -> Token {
+        let t = self.lex_token();
+        self.span.end = self.pos;
+        t
+    }
+    fn lex_token(&mut self) -> Token {
         use Token::*;
-        while let Some(&c) = self.it.peek() {
+        self.trivia_start = self.pos;
+        while let Some(c) = self.peek() {
+            self.span = Span { start: self.pos, end: self.pos, line: self.line() };
             match c {
-                c if c.is_whitespace() => { self.it.next(); }
+                c if c.is_whitespace() => { self.bump(); }
+                '/' if self.peek2() == Some('/') => {
+                    while self.peek().is_some_and(|c| c != '\n') { self.bump(); }
+                }
+                '/' if self.peek2() == Some('*') => {
+                    self.bump();
+                    self.bump();
+                    let Some(len) = self.rest().find("*/") else {
+                        while self.bump().is_some() {}
+                        return Error(String::from("unterminated comment"));
+                    };
+                    while self.pos < self.span.start + 2 + len + 2 { self.bump(); }
+                }
                 '0'..='9' => return self.lex_num(),
                 'a'..='z' | 'A'..='Z' | '_' => return self.lex_ident(),
-                '+' => { self.it.next(); return Plus; }
-                '=' => { self.it.next(); return Assign; }
-                ';' => { self.it.next(); return Semicolon; }
-                ',' => { self.it.next(); return Comma; }
-                '(' => { self.it.next(); return LParen; }
-                ')' => { self.it.next(); return RParen; }
-                '{' => { self.it.next(); return LBrace; }
-                '}' => { self.it.next(); return RBrace; }
-                '\n' => { self.it.next(); self.line_idx = self.line_idx + 1; }
-                _ => { self.it.next(); /* skip unknown */ }
+                '"' => return self.lex_str(),
+                '+' => { self.bump(); return Plus; }
+                '=' => { self.bump(); return if self.eat('=') { Eq } else { Assign }; }
+                '!' if self.peek2() == Some('=') => { self.bump(); self.bump(); return Ne; }
+                '<' => { self.bump(); return if self.eat('=') { Le } else { Lt }; }
+                '>' => { self.bump(); return if self.eat('=') { Ge } else { Gt }; }
+                '.' => {
+                    self.bump();
+                    if !self.eat('.') { return Dot; }
+                    return if self.eat('.') { Ellipsis } else { DotDot };
+                }
+                ';' => { self.bump(); return Semicolon; }
+                ',' => { self.bump(); return Comma; }
+                '(' => { self.bump(); return LParen; }
+                ')' => { self.bump(); return RParen; }
+                '{' => { self.bump(); return LBrace; }
+                '}' => { self.bump(); return RBrace; }
+                '[' => { self.bump(); return LBracket; }
+                ']' => { self.bump(); return RBracket; }
+                c => { self.bump(); return Error(format!("unexpected character {c:?}")); }
             }
         }
+        self.span = Span { start: self.pos, end: self.pos, line: self.line() };
         EOF
     }
+    fn rest(&self) -> &'a str { &self.src[self.pos..] }
+    fn peek(&self) -> Option<char> { self.rest().chars().next() }
+    /// The character after the one `peek` returns.
+    fn peek2(&self) -> Option<char> { self.rest().chars().nth(1) }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' { self.line_idx += 1; }
+        Some(c)
+    }
+    fn eat(&mut self, c: char) -> bool {
+        let hit = self.peek() == Some(c);
+        if hit { self.bump(); }
+        hit
+    }
     pub fn lex_num(&mut self) -> Token {
+        let radix = match (self.peek(), self.peek2()) {
+            (Some('0'), Some('x' | 'X')) => 16,
+            (Some('0'), Some('b' | 'B')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.bump();
+            self.bump();
+            let digits = self.lex_digits(radix);
+            return match self.malformed() {
+                Some(t) => t,
+                None if digits.is_empty() => Token::Error(format!("malformed number {:?}", &self.src[self.span.start..self.pos])),
+                None => i64::from_str_radix(&digits, radix).map_or_else(|_| self.out_of_range(), Token::Number),
+            };
+        }
+        let mut s = self.lex_digits(10);
+        let mut float = false;
+        // fraction: only when a digit follows the dot
+        if self.peek() == Some('.') && self.peek2().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+            s.push('.');
+            s += &self.lex_digits(10);
+            float = true;
+        }
+        // exponent: e, optional sign, at least one digit
+        let mut ahead = self.rest().chars();
+        if matches!(ahead.next(), Some('e' | 'E')) {
+            let mut next = ahead.next();
+            let sign = next.filter(|&c| c == '+' || c == '-');
+            if sign.is_some() { next = ahead.next(); }
+            if next.is_some_and(|c| c.is_ascii_digit()) {
+                self.bump();
+                s.push('e');
+                if let Some(sign) = sign { self.bump(); s.push(sign); }
+                s += &self.lex_digits(10);
+                float = true;
+            }
+        }
+        if let Some(t) = self.malformed() { return t; }
+        if float { return Token::Float(s.parse().unwrap()); }
+        s.parse().map_or_else(|_| self.out_of_range(), Token::Number)
+    }
+    /// Digits of `radix`, skipping `_` separators.
+    fn lex_digits(&mut self, radix: u32) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_digit(radix) { s.push(c); } else if c != '_' { break; }
+            self.bump();
+        }
+        s
+    }
+    /// An error for a number running straight into letters or digits it
+    /// cannot have, like `12ab` or `0b102`, taking in the whole word.
+    fn malformed(&mut self) -> Option<Token> {
+        if !self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') { return None; }
+        while self.peek().is_some_and(|c| c.is_alphanumeric() || c == '_') { self.bump(); }
+        Some(Token::Error(format!("malformed number {:?}", &self.src[self.span.start..self.pos])))
+    }
+    fn out_of_range(&self) -> Token {
+        Token::Error(format!("integer literal {} out of range", &self.src[self.span.start..self.pos]))
+    }
+    /// A `"`-delimited string; escapes are \n \t \r \0 \\ \", \xHH for a
+    /// byte below 0x80 and \u{H..} for any character.
+    pub fn lex_str(&mut self) -> Token {
+        self.bump(); // opening quote
         let mut s = String::new();
-        while let Some(&c) = self.it.peek() {
-            if c.is_ascii_digit() { s.push(c); self.it.next(); } else { break; }
+        let mut error = None;
+        loop {
+            let Some(c) = self.bump() else { return Token::Error(String::from("unterminated string")) };
+            match c {
+                '"' => break,
+                '\\' => match self.lex_escape() {
+                    Ok(c) => s.push(c),
+                    Err(msg) => { error.get_or_insert(msg); }
+                },
+                c => s.push(c),
+            }
         }
-        Token::Number(s.parse().unwrap())
+        error.map_or(Token::Str(s), Token::Error)
+    }
+    /// The character an escape stands for, after its `\`.
+    fn lex_escape(&mut self) -> Result<char, String> {
+        let hex = |s: &str| u32::from_str_radix(s, 16).ok();
+        Ok(match self.bump() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '"')) => c,
+            Some('x') => {
+                let s: String = self.rest().chars().take(2).collect();
+                match hex(&s).filter(|&b| s.len() == 2 && b < 0x80) {
+                    Some(b) => { self.bump(); self.bump(); char::from(b as u8) }
+                    None => return Err(String::from("invalid escape: \\x needs two hex digits below 80")),
+                }
+            }
+            Some('u') => {
+                let body = self.rest().strip_prefix('{').and_then(|r| r.find('}').map(|end| &r[..end]));
+                let Some(c) = body.and_then(hex).and_then(char::from_u32) else {
+                    return Err(String::from("invalid escape: \\u needs {hex digits} of a character"));
+                };
+                for _ in 0..body.unwrap().len() + 2 { self.bump(); }
+                c
+            }
+            Some(c) => return Err(format!("invalid escape \\{c}")),
+            None => return Err(String::from("unterminated string")),
+        })
     }
     pub fn lex_ident(&mut self) -> Token {
         let mut s = String::new();
-        while let Some(&c) = self.it.peek() {
-            if c.is_alphanumeric() || c == '_' { s.push(c); self.it.next(); } else { break; }
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' { s.push(c); self.bump(); } else { break; }
         }
         match s.as_str() {
             "print"  => Token::Print,
             "fn"     => Token::Fn,
             "return" => Token::Return,
+            "let"    => Token::Let,
+            "true"   => Token::True,
+            "false"  => Token::False,
+            "nil"    => Token::Nil,
+            "and"    => Token::And,
+            "or"     => Token::Or,
+            "not"    => Token::Not,
             _        => Token::Ident(s),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = (Token, Span);
+    fn next(&mut self) -> Option<(Token, Span)> {
+        match self.next_token() {
+            Token::EOF => None,
+            t => Some((t, self.span)),
+        }
+    }
+}
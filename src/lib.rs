@@ -0,0 +1,28 @@
+//! A minimal tracing JIT: source is compiled to stack bytecode, interpreted,
+//! and hot functions are recorded into an SSA IR closely modelled on LuaJIT's.
+
+pub mod lexer;
+pub mod bytecode;
+pub mod disasm;
+pub mod export;
+pub mod parser;
+pub mod ast;
+pub mod checker;
+pub mod cst;
+pub mod formatter;
+pub mod optimizer;
+pub mod codegen;
+pub mod vm;
+pub mod ir;
+pub mod jit;
+pub mod native;
+pub mod stdlib;
+pub mod table;
+pub mod gc;
+mod engine;
+
+pub use codegen::Module;
+pub use engine::{Engine, Error};
+pub use jit::JitOptions;
+pub use native::{Native, NativeFn};
+pub use vm::{RuntimeError, Value};
@@ -1,38 +1,13 @@
-mod lexer;
-mod bytecode;
-mod parser;
-mod ast;
-mod codegen;
-mod vm;
-mod ir;
-mod jit;
+use std::process::ExitCode;
 
-
-
-use codegen::Module;
-use parser::Parser;
-use lexer::Lexer;
-use vm::VM;
+use tiny_jit::{Engine, JitOptions};
+use tiny_jit::disasm;
+use tiny_jit::formatter::{self, FormatOptions};
 
 
 /* ================= Demo ================= */
 
-fn dump_module(m: &Module) {
-    println!("== Functions ==");
-    for (name, f) in &m.funs {
-        println!("fn {}({})", name, f.params.join(", "));
-        for (i, bc) in f.code.iter().enumerate() {
-            println!("  {:04}: {:?}", i, bc);
-        }
-    }
-    println!("\n== main ==");
-    for (i, bc) in m.main.code.iter().enumerate() {
-        println!("  {:04}: {:?}", i, bc);
-    }
-}
-
-fn main() {
-    let program = r#"
+const DEMO: &str = r#"
         fn add(a, b) {
             return a + b;
         }
@@ -50,16 +25,78 @@ fn main() {
         print add(x + y, 7);
     "#;
 
-    // Frontend
-    let mut parser = Parser::new(Lexer::new(program));
-    let ast = parser.parse_program();
+/// `[-O0 | -O1] [-jv] [-jdump] [path]`: runs the program at `path`, or the
+/// demo; `-O0` compiles it without the AST optimizer, `-jv` logs what the
+/// JIT does and `-jdump` prints every trace it makes.
+fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut optimize = true;
+    let mut opts = JitOptions::default();
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "-O0" => optimize = false,
+            "-O1" => optimize = true,
+            "-jv" => opts.verbose = true,
+            "-jdump" => opts.dump = true,
+            _ => path = Some(arg),
+        }
+    }
+    let program = match path {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => DEMO.to_string(),
+    };
+
+    let mut engine = Engine::with_options(opts);
+    engine.set_optimize(optimize);
 
     // Compile
-    let module = codegen::compile_module(ast);
-    dump_module(&module);
+    let (module, warnings) = engine.compile_with_warnings(&program)?;
+    for d in &warnings { eprintln!("{d}"); }
+    println!("== Bytecode ==");
+    print!("{}", disasm::disassemble(&module, Some(&program)));
 
     // Run
     println!("\n== Program output ==");
-    let mut vm = VM::new(&module);
-    vm.run_main();
+    engine.load(module);
+    engine.run_main()?;
+    Ok(())
+}
+
+/// `fmt [--indent N | --tabs] [path]`: prints the program at `path`, or on
+/// stdin, formatted.
+fn fmt(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut opts = FormatOptions::default();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--indent" => {
+                let n: usize = args.next().ok_or("--indent needs a number")?.parse()?;
+                opts.indent = " ".repeat(n);
+            }
+            "--tabs" => opts.indent = String::from("\t"),
+            _ => path = Some(arg),
+        }
+    }
+    let src = match path {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    print!("{}", formatter::format(&src, &opts)?);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first() {
+        Some(cmd) if cmd == "fmt" => fmt(&args[1..]),
+        _ => run(&args),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -0,0 +1,38 @@
+/* ================= Native functions ================= */
+
+use std::collections::HashMap;
+
+use crate::ir::IROp;
+use crate::vm::{RuntimeError, Value, VM};
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+#[derive(Clone)]
+pub struct Native {
+    pub name: String,
+    /// `None` accepts any number of arguments.
+    pub arity: Option<usize>,
+    /// Pure natives depend only on their arguments and have no side effects,
+    /// so the JIT may fold them on constants and CSE repeated calls.
+    pub pure: bool,
+    /// IR instruction the recorder emits instead of a call, for natives it
+    /// knows the semantics of.
+    pub intrinsic: Option<IROp>,
+    pub f: NativeFn,
+}
+
+/// Host functions scripts can call by name, consulted after `Module::funs`.
+#[derive(Clone, Default)]
+pub struct Natives {
+    map: HashMap<String, Native>,
+}
+
+impl Natives {
+    pub fn register(&mut self, native: Native) {
+        self.map.insert(native.name.clone(), native);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Native> { self.map.get(name) }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Native> { self.map.values() }
+}
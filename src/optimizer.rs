@@ -0,0 +1,332 @@
+/* ================= AST optimizer ================= */
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{Expr, Function, Stmt};
+use crate::checker;
+use crate::vm::{self, Value};
+
+/// Largest body, in expression nodes, of a function calls to it are inlined.
+const MAX_INLINE: usize = 12;
+
+/// Rewrites `program` to do the same with less work before it is compiled:
+/// constant expressions are folded, constants assigned in straight-line code
+/// replace later reads of their variable, and calls to tiny functions that
+/// only `return` an expression of their parameters are inlined.
+///
+/// Nothing that could fail at run time is folded, so errors stay where they
+/// were. Functions named like a global the host has, as `is_global` tells,
+/// are not inlined: such a global wins over the function when called.
+pub fn optimize(program: &mut [Stmt], is_global: &dyn Fn(&str) -> bool) {
+    let mut names = HashSet::new();
+    checker::declared(program, &mut names, true);
+    let inline = program.iter().filter_map(|s| match s {
+        Stmt::FunctionDef(f) if !names.contains(f.name.as_str()) && !is_global(&f.name) => inlinable(f).map(|e| (f.name.clone(), (f.params.clone(), e.clone()))),
+        _ => None,
+    }).collect();
+    let mut written = HashSet::new();
+    nested_writes(program, &mut written, true);
+    let written = written.into_iter().map(String::from).collect();
+    let mut o = Optimizer { inline, written, scopes: vec![HashMap::new()] };
+    o.body(program);
+}
+
+/// What is known about a variable in straight-line code.
+#[derive(Clone)]
+struct Fact {
+    /// A constant it holds.
+    value: Option<Expr>,
+    /// A `let` or parameter, which only its function and the functions
+    /// nested in it can assign.
+    local: bool,
+}
+
+struct Optimizer {
+    /// Functions calls are inlined for: parameters and the returned expression.
+    inline: HashMap<String, (Vec<String>, Expr)>,
+    /// Names some nested function assigns, declares or takes as a parameter.
+    written: HashSet<String>,
+    /// Facts of the function being optimized, per open block, innermost last.
+    /// The first holds its parameters and plain variables.
+    scopes: Vec<HashMap<String, Fact>>,
+}
+
+fn is_const(e: &Expr) -> bool {
+    matches!(e, Expr::Number(_) | Expr::Float(_) | Expr::Str(_) | Expr::Bool(_) | Expr::Nil)
+}
+
+fn value(e: &Expr) -> Option<Value> {
+    Some(match e {
+        Expr::Number(n) => Value::Int(*n),
+        Expr::Float(x) => Value::Float(*x),
+        Expr::Str(s) => Value::Str(s.as_str().into()),
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Nil => Value::Nil,
+        _ => return None,
+    })
+}
+
+fn literal(v: Value) -> Expr {
+    match v {
+        Value::Int(n) => Expr::Number(n),
+        Value::Float(x) => Expr::Float(x),
+        Value::Str(s) => Expr::Str(s.to_string()),
+        Value::Bool(b) => Expr::Bool(b),
+        _ => Expr::Nil,
+    }
+}
+
+/// Whether `e` in a value list may stand for several values.
+fn spreads(e: &Expr) -> bool {
+    matches!(e, Expr::Call(..) | Expr::Vararg)
+}
+
+/// The expression `f` returns, if calls to it can be inlined: it is all of
+/// its body, small, and made of nothing but parameters, constants and
+/// operators that cannot call anything.
+fn inlinable(f: &Function) -> Option<&Expr> {
+    fn size(e: &Expr, params: &[String]) -> Option<usize> {
+        match e {
+            Expr::Var(name) if params.contains(name) => Some(1),
+            e if is_const(e) => Some(1),
+            Expr::Add(a, b) | Expr::Concat(a, b) | Expr::Compare(_, a, b) | Expr::And(a, b) | Expr::Or(a, b) => {
+                Some(1 + size(a, params)? + size(b, params)?)
+            }
+            Expr::Not(a) => Some(1 + size(a, params)?),
+            _ => None,
+        }
+    }
+    let [Stmt::Return(values, _)] = &f.body[..] else { return None };
+    let [e] = &values[..] else { return None };
+    if f.variadic || size(e, &f.params)? > MAX_INLINE { return None; }
+    Some(e)
+}
+
+/// The parameters `e` reads, in the order it reads them, and whether it
+/// may skip some of them.
+fn reads<'a>(e: &'a Expr, out: &mut Vec<&'a str>) -> bool {
+    if let Expr::Var(name) = e { out.push(name); }
+    let mut skips = matches!(e, Expr::And(..) | Expr::Or(..));
+    for o in e.operands() { skips |= reads(o, out); }
+    skips
+}
+
+/// Every name some function nested in `body` assigns, declares or takes as
+/// a parameter. Functions defined at the `top` level are not nested.
+fn nested_writes<'a>(body: &'a [Stmt], out: &mut HashSet<&'a str>, top: bool) {
+    fn in_fn<'a>(f: &'a Function, out: &mut HashSet<&'a str>) {
+        out.extend(f.params.iter().map(String::as_str));
+        checker::declared(&f.body, out, false);
+    }
+    fn in_expr<'a>(e: &'a Expr, out: &mut HashSet<&'a str>) {
+        if let Expr::Function(f) = e { in_fn(f, out); }
+        for o in e.operands() { in_expr(o, out); }
+    }
+    for s in body {
+        match s {
+            Stmt::Block(b) => nested_writes(b, out, false),
+            Stmt::FunctionDef(f) if top => nested_writes(&f.body, out, false),
+            Stmt::FunctionDef(f) => in_fn(f, out),
+            _ => {}
+        }
+        for e in s.exprs() { in_expr(e, out); }
+    }
+}
+
+impl Optimizer {
+    fn fact(&mut self, name: &str) -> Option<&mut Fact> {
+        self.scopes.iter_mut().rev().find_map(|s| s.get_mut(name))
+    }
+
+    /// Records what `name` holds after an assignment to it.
+    fn assign(&mut self, name: &str, value: Option<Expr>) {
+        match self.fact(name) {
+            Some(fact) => fact.value = value,
+            None => { self.scopes[0].insert(name.to_string(), Fact { value, local: false }); }
+        }
+    }
+
+    /// Forgets what a call may change: variables other than locals, and
+    /// locals a nested function assigns.
+    fn call_made(&mut self) {
+        let written = &self.written;
+        for (name, fact) in self.scopes.iter_mut().flatten() {
+            if !fact.local || written.contains(name) { fact.value = None; }
+        }
+    }
+
+    /// The constants `values` leave in `n` targets, where known. Targets
+    /// past the values get nil, unless the last value spreads over them.
+    fn constants(values: &[Expr], n: usize) -> Vec<Option<Expr>> {
+        let spread = values.last().is_some_and(spreads);
+        (0..n).map(|i| match values.get(i) {
+            Some(e) => Some(e.clone()).filter(is_const),
+            None => Some(Expr::Nil).filter(|_| !spread),
+        }).collect()
+    }
+
+    /// Optimizes a function body with nothing known but its parameters.
+    fn function(&mut self, f: &mut Function) {
+        let params = f.params.iter().map(|p| (p.clone(), Fact { value: None, local: true })).collect();
+        let outer = std::mem::replace(&mut self.scopes, vec![params]);
+        self.block(&mut f.body);
+        self.scopes = outer;
+    }
+
+    fn block(&mut self, body: &mut [Stmt]) {
+        self.scopes.push(HashMap::new());
+        self.body(body);
+        self.scopes.pop();
+    }
+
+    fn body(&mut self, body: &mut [Stmt]) {
+        for s in body { self.stmt(s); }
+    }
+
+    fn stmt(&mut self, s: &mut Stmt) {
+        match s {
+            Stmt::Assign(name, e, _) => {
+                self.expr(e);
+                let value = Some(e.clone()).filter(is_const);
+                self.assign(name, value);
+            }
+            Stmt::SetIndex(t, k, v, _) => { self.expr(t); self.expr(k); self.expr(v); }
+            Stmt::MultiAssign(names, values, _) => {
+                for e in values.iter_mut() { self.expr(e); }
+                // stored last to first, so the first of a repeated name stays
+                for (name, value) in names.iter().zip(Self::constants(values, names.len())).rev() {
+                    self.assign(name, value);
+                }
+            }
+            Stmt::Let(names, values, _) => {
+                for e in values.iter_mut() { self.expr(e); }
+                // at the top level of main, outside any block, a `let` declares a global
+                let local = self.scopes.len() > 1;
+                for (name, value) in names.iter().zip(Self::constants(values, names.len())).rev() {
+                    self.scopes.last_mut().unwrap().insert(name.clone(), Fact { value, local });
+                }
+            }
+            Stmt::Block(b) => self.block(b),
+            Stmt::Print(e, _) | Stmt::Expr(e, _) => self.expr(e),
+            Stmt::Return(values, _) => for e in values { self.expr(e) },
+            Stmt::FunctionDef(f) => {
+                // a nested definition is a local, known to hold a function
+                if self.scopes.len() > 1 {
+                    self.scopes.last_mut().unwrap().insert(f.name.clone(), Fact { value: None, local: true });
+                }
+                self.function(f);
+            }
+        }
+    }
+
+    /// Optimizes `e` in place, operands first, in the order they are evaluated.
+    fn expr(&mut self, e: &mut Expr) {
+        match e {
+            Expr::Var(name) => {
+                if let Some(value) = self.fact(name).and_then(|f| f.value.clone()) { *e = value; }
+                return;
+            }
+            Expr::Function(f) => return self.function(f),
+            Expr::Call(callee, args, _) => {
+                // the callee is looked up by name, not read as a variable
+                if !matches!(**callee, Expr::Var(_)) { self.expr(callee); }
+                for a in args.iter_mut() { self.expr(a); }
+                match self.inlined(callee, args) {
+                    Some(body) => *e = body,
+                    None => { self.call_made(); return; }
+                }
+            }
+            Expr::Add(a, b) | Expr::Concat(a, b) | Expr::Compare(_, a, b) | Expr::And(a, b) | Expr::Or(a, b)
+            | Expr::Index(a, b) => { self.expr(a); self.expr(b); }
+            Expr::Not(a) | Expr::Paren(a) => self.expr(a),
+            Expr::Table(items, fields) => {
+                for i in items { self.expr(i); }
+                for (k, v) in fields { self.expr(k); self.expr(v); }
+            }
+            _ => return,
+        }
+        fold(e);
+    }
+
+    /// The inlined body of a call, if the callee is a function calls to
+    /// which are inlined, and every argument is evaluated exactly as the
+    /// call would: each is a constant or variable, or the body reads each
+    /// parameter once, in order and without skipping any.
+    fn inlined(&self, callee: &Expr, args: &[Expr]) -> Option<Expr> {
+        let Expr::Var(name) = callee else { return None };
+        let (params, body) = self.inline.get(name)?;
+        if args.len() != params.len() || args.last().is_some_and(spreads) { return None; }
+        if !args.iter().all(|a| is_const(a) || matches!(a, Expr::Var(_))) {
+            let mut order = Vec::new();
+            if reads(body, &mut order) || !order.iter().copied().eq(params.iter().map(String::as_str)) { return None; }
+        }
+        let mut body = body.clone();
+        substitute(&mut body, params, args);
+        refold(&mut body);
+        Some(body)
+    }
+}
+
+/// Replaces reads of `params` in `e` with the matching `args`.
+fn substitute(e: &mut Expr, params: &[String], args: &[Expr]) {
+    match e {
+        Expr::Var(name) => if let Some(i) = params.iter().position(|p| p == name) { *e = args[i].clone() },
+        Expr::Add(a, b) | Expr::Concat(a, b) | Expr::Compare(_, a, b) | Expr::And(a, b) | Expr::Or(a, b) => {
+            substitute(a, params, args);
+            substitute(b, params, args);
+        }
+        Expr::Not(a) => substitute(a, params, args),
+        _ => {}
+    }
+}
+
+/// Folds an inlined body bottom-up; its arguments are folded already.
+fn refold(e: &mut Expr) {
+    match e {
+        Expr::Add(a, b) | Expr::Concat(a, b) | Expr::Compare(_, a, b) | Expr::And(a, b) | Expr::Or(a, b) => {
+            refold(a);
+            refold(b);
+        }
+        Expr::Not(a) => refold(a),
+        _ => return,
+    }
+    fold(e);
+}
+
+/// Folds `e` if its operands, folded already, are constants and the
+/// operation cannot fail.
+fn fold(e: &mut Expr) {
+    let folded = match e {
+        Expr::Add(a, b) => match (value(a), value(b)) {
+            (Some(x), Some(y)) => vm::arith_add(&x, &y).ok().map(literal),
+            _ => None,
+        },
+        Expr::Concat(a, b) => match (value(a), value(b)) {
+            (Some(x), Some(y)) if [&x, &y].iter().all(|v| matches!(v, Value::Str(_) | Value::Int(_) | Value::Float(_))) => {
+                Some(Expr::Str(format!("{x}{y}")))
+            }
+            _ => None,
+        },
+        Expr::Compare(op, a, b) => match (value(a), value(b)) {
+            (Some(x), Some(y)) => vm::compare(*op, &x, &y).ok().map(Expr::Bool),
+            _ => None,
+        },
+        Expr::Not(a) => value(a).map(|v| Expr::Bool(!v.is_truthy())),
+        Expr::And(a, b) => short_circuit(a, b, false),
+        Expr::Or(a, b) => short_circuit(a, b, true),
+        Expr::Paren(a) if !spreads(a) => Some((**a).clone()),
+        _ => None,
+    };
+    if let Some(folded) = folded { *e = folded; }
+}
+
+/// `a and b` or `a or b`, for a constant `a`: `a` itself if it is
+/// `truthy` enough to decide, else `b`. A `b` that could spread into
+/// several values is left alone, `and` and `or` keep only the first.
+fn short_circuit(a: &Expr, b: &Expr, truthy: bool) -> Option<Expr> {
+    match value(a) {
+        Some(v) if v.is_truthy() == truthy => Some(a.clone()),
+        Some(_) if !spreads(b) => Some(b.clone()),
+        _ => None,
+    }
+}
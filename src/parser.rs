@@ -1,132 +1,481 @@
 
 /* ================= Parser ================= */
 
-use crate::lexer::{Lexer,Token};
-use crate::ast::{Stmt, Function, Expr};
+use std::fmt;
+
+use crate::lexer::{Lexer, Span, Token};
+use crate::ast::{Stmt, Function, Expr, CmpOp};
+use crate::cst::{self, Kind, Leaf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type PResult<T> = Result<T, ParseError>;
 
 pub struct Parser<'a> {
     lex: Lexer<'a>,
     cur: Token,
+    line: usize,
+    // end of the token before `cur`
+    prev_end: usize,
+    // whether the function being parsed may use `...`
+    variadic: bool,
+    // the first Error token read; the parser stops at it, whatever it expected
+    lex_error: Option<ParseError>,
+    // the syntax tree built alongside the AST, if asked for, and the leaf of
+    // `cur` that goes into it once `cur` is consumed
+    cst: Option<cst::Builder>,
+    leaf: Option<Leaf>,
 }
 impl<'a> Parser<'a> {
-    pub fn new(mut lex: Lexer<'a>) -> Self { let cur = lex.next_token(); Self { lex, cur } }
-    pub fn bump(&mut self) { self.cur = self.lex.next_token(); }
-    pub fn expect(&mut self, want: &Token) {
-        if &self.cur != want { 
-            panic!("Expected {:?}, got {:?}", want, self.cur);
+    pub fn new(lex: Lexer<'a>) -> Self {
+        let mut p = Self { lex, cur: Token::EOF, line: 1, prev_end: 0, variadic: false, lex_error: None, cst: None, leaf: None };
+        p.bump();
+        p
+    }
+    /// A parser that also builds the lossless syntax tree of the program,
+    /// which `syntax_tree` returns after `parse_program`.
+    pub fn with_cst(lex: Lexer<'a>) -> Self {
+        let mut p = Self::new(lex);
+        p.cst = Some(cst::Builder::default());
+        p.leaf = Some(p.cur_leaf());
+        p
+    }
+    pub fn syntax_tree(&mut self) -> Option<cst::Node> {
+        self.cst.take()?.root()
+    }
+    fn cur_leaf(&self) -> Leaf {
+        Leaf { token: self.cur.clone(), trivia: self.lex.trivia().to_string(), text: self.lex.text().to_string() }
+    }
+    fn start(&mut self, kind: Kind) {
+        if let Some(b) = &mut self.cst { b.start(kind); }
+    }
+    fn checkpoint(&self) -> usize {
+        self.cst.as_ref().map_or(0, cst::Builder::checkpoint)
+    }
+    fn start_at(&mut self, checkpoint: usize, kind: Kind) {
+        if let Some(b) = &mut self.cst { b.start_at(checkpoint, kind); }
+    }
+    fn finish(&mut self) {
+        if let Some(b) = &mut self.cst { b.finish(); }
+    }
+    pub fn bump(&mut self) {
+        if let (Some(b), Some(leaf)) = (&mut self.cst, self.leaf.take()) { b.push(leaf); }
+        self.prev_end = self.lex.span().end;
+        self.cur = self.lex.next_token();
+        if self.cst.is_some() { self.leaf = Some(self.cur_leaf()); }
+        self.line = self.lex.line();
+        if let Token::Error(msg) = &self.cur && self.lex_error.is_none() {
+            self.lex_error = Some(ParseError { line: self.lex.span().line, msg: msg.clone() });
+        }
+    }
+    /// Span from `start` to the end of the last token consumed.
+    fn span_from(&self, start: Span) -> Span {
+        Span { end: self.prev_end, ..start }
+    }
+    fn error<T>(&self, msg: String) -> PResult<T> {
+        if let Some(e) = &self.lex_error { return Err(e.clone()); }
+        Err(ParseError { line: self.line, msg })
+    }
+    pub fn expect(&mut self, want: &Token) -> PResult<()> {
+        if &self.cur != want {
+            return self.error(format!("Expected {:?}, got {:?}", want, self.cur));
         }
         self.bump();
+        Ok(())
     }
 
-    pub fn parse_program(&mut self) -> Vec<Stmt> {
+    pub fn parse_program(&mut self) -> PResult<Vec<Stmt>> {
         let mut v = Vec::new();
+        self.start(Kind::Program);
         loop {
             match &self.cur {
                 Token::EOF => break,
-                Token::Fn => v.push(Stmt::FunctionDef(self.parse_fn())),
-                _ => v.push(self.parse_stmt()),
+                _ => v.push(self.parse_stmt()?),
             }
         }
-        v
+        // the EOF leaf keeps the trivia after the last token
+        self.bump();
+        self.finish();
+        Ok(v)
     }
 
-    pub fn parse_fn(&mut self) -> Function {
-        self.expect(&Token::Fn);
+    pub fn parse_fn(&mut self) -> PResult<Function> {
+        self.start(Kind::FnDef);
+        self.expect(&Token::Fn)?;
+        let span = self.lex.span();
         let name = match std::mem::replace(&mut self.cur, Token::EOF) {
             Token::Ident(s) => { self.bump(); s }
-            t => panic!("fn name expected, got {:?}", t),
+            t => return self.error(format!("fn name expected, got {:?}", t)),
         };
-        self.expect(&Token::LParen);
+        let f = self.parse_fn_rest(name, span)?;
+        self.finish();
+        Ok(f)
+    }
+
+    /// Parameter list and body of a function, after its name if it has one.
+    fn parse_fn_rest(&mut self, name: String, span: Span) -> PResult<Function> {
+        self.start(Kind::Params);
+        self.expect(&Token::LParen)?;
         let mut params = Vec::new();
+        let mut variadic = false;
         if self.cur != Token::RParen {
             loop {
                 match std::mem::replace(&mut self.cur, Token::EOF) {
                     Token::Ident(s) => { self.bump(); params.push(s); }
-                    t => panic!("param name expected, got {:?}", t),
+                    // `...` can only come last
+                    Token::Ellipsis => { self.bump(); variadic = true; break; }
+                    t => return self.error(format!("param name expected, got {:?}", t)),
                 }
                 if self.cur == Token::Comma { self.bump(); continue; }
                 break;
             }
         }
-        self.expect(&Token::RParen);
-        self.expect(&Token::LBrace);
+        self.expect(&Token::RParen)?;
+        self.finish();
+        let outer = std::mem::replace(&mut self.variadic, variadic);
+        let body = self.parse_block();
+        self.variadic = outer;
+        Ok(Function { name, params, variadic, body: body?, span })
+    }
+
+    /// `{ statements }`.
+    fn parse_block(&mut self) -> PResult<Vec<Stmt>> {
+        self.start(Kind::Block);
+        self.expect(&Token::LBrace)?;
         let mut body = Vec::new();
         while self.cur != Token::RBrace {
-            body.push(self.parse_stmt());
+            body.push(self.parse_stmt()?);
         }
-        self.expect(&Token::RBrace);
-        Function { name, params, body }
+        self.expect(&Token::RBrace)?;
+        self.finish();
+        Ok(body)
     }
 
-    pub fn parse_stmt(&mut self) -> Stmt {
+    pub fn parse_stmt(&mut self) -> PResult<Stmt> {
         use Token::*;
         match &self.cur {
-            Print => { self.bump(); let e = self.parse_expr(); self.expect(&Semicolon); Stmt::Print(e) }
-            Return => { self.bump(); let e = self.parse_expr(); self.expect(&Semicolon); Stmt::Return(e) }
+            Print => {
+                self.start(Kind::Print);
+                let start = self.lex.span();
+                self.bump();
+                let e = self.parse_expr()?;
+                self.expect(&Semicolon)?;
+                self.finish();
+                Ok(Stmt::Print(e, self.span_from(start)))
+            }
+            Return => {
+                self.start(Kind::Return);
+                let start = self.lex.span();
+                self.bump();
+                let values = if self.cur == Semicolon { Vec::new() } else { self.parse_exprs()? };
+                self.expect(&Semicolon)?;
+                self.finish();
+                Ok(Stmt::Return(values, self.span_from(start)))
+            }
+            Let => {
+                self.start(Kind::Let);
+                let start = self.lex.span();
+                self.bump();
+                let mut names = Vec::new();
+                loop {
+                    match std::mem::replace(&mut self.cur, Token::EOF) {
+                        Ident(s) => { self.bump(); names.push(s); }
+                        t => return self.error(format!("variable name expected, got {:?}", t)),
+                    }
+                    if self.cur != Comma { break; }
+                    self.bump();
+                }
+                let values = if self.cur == Assign { self.bump(); self.parse_exprs()? } else { Vec::new() };
+                self.expect(&Semicolon)?;
+                self.finish();
+                Ok(Stmt::Let(names, values, self.span_from(start)))
+            }
+            LBrace => Ok(Stmt::Block(self.parse_block()?)),
+            Fn => Ok(Stmt::FunctionDef(self.parse_fn()?)),
             Ident(name) => {
                 // either assignment or call-statement (we support call as expr too)
                 let name_clone = name.clone();
+                let cp = self.checkpoint();
+                let start = self.lex.span();
                 self.bump();
-                match &self.cur {
-                    Assign => {
-                        self.bump();
-                        let e = self.parse_expr();
-                        self.expect(&Semicolon);
-                        Stmt::Assign(name_clone, e)
-                    }
-                    LParen => {
-                        // call as statement
-                        let call = self.finish_call(name_clone);
-                        self.expect(&Semicolon);
-                        // desugar: tmp = call; print? For now we just evaluate and discard.
-                        Stmt::Print(call) // (or create a Stmt::Expr(call) variant; we’ll just print)
-                    }
-                    _ => panic!("unexpected token after ident in stmt: {:?}", self.cur),
+                if !matches!(self.cur, Assign | LBracket | Dot | LParen | Comma) {
+                    return self.error(format!("unexpected token after ident in stmt: {:?}", self.cur));
+                }
+                let target = self.parse_postfix(cp, start, Expr::Var(name_clone))?;
+                if let Expr::Call(..) = target && self.cur != Assign {
+                    // call as statement
+                    self.start_at(cp, Kind::ExprStmt);
+                    self.expect(&Semicolon)?;
+                    self.finish();
+                    return Ok(Stmt::Expr(target, self.span_from(start)));
+                }
+                self.start_at(cp, Kind::Assign);
+                if self.cur == Comma {
+                    return self.parse_multi_assign(start, target);
+                }
+                self.expect(&Assign)?;
+                let e = self.parse_expr()?;
+                self.expect(&Semicolon)?;
+                self.finish();
+                let span = self.span_from(start);
+                match target {
+                    Expr::Index(t, k) => Ok(Stmt::SetIndex(*t, *k, e, span)),
+                    Expr::Var(name) => Ok(Stmt::Assign(name, e, span)),
+                    _ => self.error(String::from("cannot assign to a call")),
                 }
             }
-            _ => panic!("bad stmt start: {:?}", self.cur),
+            _ => self.error(format!("bad stmt start: {:?}", self.cur)),
+        }
+    }
+
+    /// `x, y, ... = values;`, after the first target, which began at `start`.
+    fn parse_multi_assign(&mut self, start: Span, first: Expr) -> PResult<Stmt> {
+        let mut targets = vec![first];
+        while self.cur == Token::Comma {
+            self.bump();
+            let t = self.parse_atom()?;
+            targets.push(t);
+        }
+        let names = targets.into_iter().map(|t| match t {
+            Expr::Var(name) => Ok(name),
+            _ => self.error(String::from("only variables can be assigned together")),
+        }).collect::<PResult<Vec<_>>>()?;
+        self.expect(&Token::Assign)?;
+        let values = self.parse_exprs()?;
+        self.expect(&Token::Semicolon)?;
+        self.finish();
+        Ok(Stmt::MultiAssign(names, values, self.span_from(start)))
+    }
+
+    /// One or more comma separated expressions.
+    fn parse_exprs(&mut self) -> PResult<Vec<Expr>> {
+        let mut v = vec![self.parse_expr()?];
+        while self.cur == Token::Comma {
+            self.bump();
+            v.push(self.parse_expr()?);
         }
+        Ok(v)
     }
 
-    fn parse_expr(&mut self) -> Expr {
-        let mut lhs = self.parse_atom();
+    fn parse_expr(&mut self) -> PResult<Expr> {
+        let cp = self.checkpoint();
+        let mut lhs = self.parse_and()?;
+        while self.cur == Token::Or {
+            self.start_at(cp, Kind::Binary);
+            self.bump();
+            let rhs = self.parse_and()?;
+            self.finish();
+            lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> PResult<Expr> {
+        let cp = self.checkpoint();
+        let mut lhs = self.parse_not()?;
+        while self.cur == Token::And {
+            self.start_at(cp, Kind::Binary);
+            self.bump();
+            let rhs = self.parse_not()?;
+            self.finish();
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_not(&mut self) -> PResult<Expr> {
+        if self.cur == Token::Not {
+            self.start(Kind::Not);
+            self.bump();
+            let e = self.parse_not()?;
+            self.finish();
+            return Ok(Expr::Not(Box::new(e)));
+        }
+        self.parse_cmp()
+    }
+
+    fn parse_cmp(&mut self) -> PResult<Expr> {
+        let cp = self.checkpoint();
+        let mut lhs = self.parse_concat()?;
+        loop {
+            let op = match self.cur {
+                Token::Eq => CmpOp::Eq,
+                Token::Ne => CmpOp::Ne,
+                Token::Lt => CmpOp::Lt,
+                Token::Le => CmpOp::Le,
+                Token::Gt => CmpOp::Gt,
+                Token::Ge => CmpOp::Ge,
+                _ => return Ok(lhs),
+            };
+            self.start_at(cp, Kind::Binary);
+            self.bump();
+            let rhs = self.parse_concat()?;
+            self.finish();
+            lhs = Expr::Compare(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    /// `..` is right associative, like Lua's.
+    fn parse_concat(&mut self) -> PResult<Expr> {
+        let cp = self.checkpoint();
+        let lhs = self.parse_sum()?;
+        if self.cur == Token::DotDot {
+            self.start_at(cp, Kind::Binary);
+            self.bump();
+            let rhs = self.parse_concat()?;
+            self.finish();
+            return Ok(Expr::Concat(Box::new(lhs), Box::new(rhs)));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_sum(&mut self) -> PResult<Expr> {
+        let cp = self.checkpoint();
+        let mut lhs = self.parse_atom()?;
         while self.cur == Token::Plus {
+            self.start_at(cp, Kind::Binary);
             self.bump();
-            let rhs = self.parse_atom();
+            let rhs = self.parse_atom()?;
+            self.finish();
             lhs = Expr::Add(Box::new(lhs), Box::new(rhs));
         }
-        lhs
+        Ok(lhs)
     }
 
-    fn parse_atom(&mut self) -> Expr {
-        use Token::*;
-        match std::mem::replace(&mut self.cur, Token::EOF) {
-            Number(n) => { self.bump(); Expr::Number(n) }
-            Ident(s) => {
-                // could be var or call
-                self.bump(); // consume '('
-                if self.cur == LParen {
+    fn parse_atom(&mut self) -> PResult<Expr> {
+        let cp = self.checkpoint();
+        let start = self.lex.span();
+        let e = self.parse_primary()?;
+        self.parse_postfix(cp, start, e)
+    }
+
+    /// Applies any `[k]`, `.name` and `(args)` suffixes to `e`, which
+    /// started at `cp` with the token at `start`.
+    fn parse_postfix(&mut self, cp: usize, start: Span, mut e: Expr) -> PResult<Expr> {
+        loop {
+            match self.cur {
+                Token::LParen => {
+                    self.start_at(cp, Kind::Call);
+                    self.start(Kind::Args);
+                    self.bump();
+                    e = self.finish_call(e, start)?;
+                    self.finish();
+                    self.finish();
+                }
+                Token::LBracket => {
+                    self.start_at(cp, Kind::Index);
                     self.bump();
-                    return self.finish_call(s);
-                } else {
-                    Expr::Var(s)
+                    let k = self.parse_expr()?;
+                    self.expect(&Token::RBracket)?;
+                    self.finish();
+                    e = Expr::Index(Box::new(e), Box::new(k));
                 }
+                Token::Dot => {
+                    self.start_at(cp, Kind::Member);
+                    self.bump();
+                    let name = match std::mem::replace(&mut self.cur, Token::EOF) {
+                        Token::Ident(s) => { self.bump(); s }
+                        t => return self.error(format!("field name expected, got {:?}", t)),
+                    };
+                    self.finish();
+                    e = Expr::Index(Box::new(e), Box::new(Expr::Str(name)));
+                }
+                _ => return Ok(e),
             }
-            t => panic!("atom expected, got {:?}", t),
         }
     }
 
-    fn finish_call(&mut self, name: String) -> Expr {
-        let mut args = Vec::new();
-        if self.cur != Token::RParen {
-            loop {
-                let e = self.parse_expr();
-                args.push(e);
-                if self.cur == Token::Comma { self.bump(); continue; }
-                break;
+    /// `{ a, b, name = v, [k] = v }` with an optional trailing comma.
+    fn parse_table(&mut self) -> PResult<Expr> {
+        let mut array = Vec::new();
+        let mut fields = Vec::new();
+        while self.cur != Token::RBrace {
+            match &self.cur {
+                Token::LBracket => {
+                    self.start(Kind::Field);
+                    self.bump();
+                    let k = self.parse_expr()?;
+                    self.expect(&Token::RBracket)?;
+                    self.expect(&Token::Assign)?;
+                    fields.push((k, self.parse_expr()?));
+                    self.finish();
+                }
+                Token::Ident(name) if self.lex.clone().next_token() == Token::Assign => {
+                    let k = Expr::Str(name.clone());
+                    self.start(Kind::Field);
+                    self.bump();
+                    self.bump();
+                    fields.push((k, self.parse_expr()?));
+                    self.finish();
+                }
+                _ => array.push(self.parse_expr()?),
+            }
+            if self.cur == Token::Comma { self.bump(); continue; }
+            break;
+        }
+        self.expect(&Token::RBrace)?;
+        Ok(Expr::Table(array, fields))
+    }
+
+    fn parse_primary(&mut self) -> PResult<Expr> {
+        use Token::*;
+        match std::mem::replace(&mut self.cur, Token::EOF) {
+            Number(n) => { self.bump(); Ok(Expr::Number(n)) }
+            Float(x) => { self.bump(); Ok(Expr::Float(x)) }
+            Str(s) => { self.bump(); Ok(Expr::Str(s)) }
+            True => { self.bump(); Ok(Expr::Bool(true)) }
+            False => { self.bump(); Ok(Expr::Bool(false)) }
+            Nil => { self.bump(); Ok(Expr::Nil) }
+            LParen => {
+                self.start(Kind::Paren);
+                self.bump();
+                let e = self.parse_expr()?;
+                self.expect(&RParen)?;
+                self.finish();
+                // only a call or `...` gives more than one value to cut
+                Ok(if matches!(e, Expr::Call(..) | Expr::Vararg) { Expr::Paren(Box::new(e)) } else { e })
             }
+            LBrace => {
+                self.start(Kind::Table);
+                self.bump();
+                let t = self.parse_table()?;
+                self.finish();
+                Ok(t)
+            }
+            Fn => {
+                self.start(Kind::FnExpr);
+                let span = self.lex.span();
+                self.bump();
+                let f = self.parse_fn_rest(String::new(), span)?;
+                self.finish();
+                Ok(Expr::Function(f))
+            }
+            Ellipsis if self.variadic => { self.bump(); Ok(Expr::Vararg) }
+            Ellipsis => self.error(String::from("cannot use `...` outside a variadic function")),
+            Ident(s) => {
+                // calls are a postfix
+                self.bump();
+                Ok(Expr::Var(s))
+            }
+            t => self.error(format!("atom expected, got {:?}", t)),
         }
-        self.expect(&Token::RParen);
-        Expr::Call(name, args)
+    }
+
+    fn finish_call(&mut self, callee: Expr, start: Span) -> PResult<Expr> {
+        let args = if self.cur == Token::RParen { Vec::new() } else { self.parse_exprs()? };
+        self.expect(&Token::RParen)?;
+        Ok(Expr::Call(Box::new(callee), args, self.span_from(start)))
     }
 }
@@ -0,0 +1,142 @@
+/* ================= Standard library ================= */
+
+use crate::ir::IROp;
+use crate::native::{Native, NativeFn, Natives};
+use crate::vm::{expect_num, values_equal, RuntimeError, Value, VM};
+
+/// Registers the builtins every VM starts with.
+pub fn open(natives: &mut Natives) {
+    // pure math, inlined by the recorder as IR instructions
+    for (name, arity, op, f) in [
+        ("abs", 1, IROp::Abs, abs as NativeFn),
+        ("min", 2, IROp::Min, min),
+        ("max", 2, IROp::Max, max),
+        ("pow", 2, IROp::Pow, pow),
+    ] {
+        natives.register(Native { name: name.to_string(), arity: Some(arity), pure: true, intrinsic: Some(op), f });
+    }
+
+    for (name, arity, f) in [
+        ("len", Some(1), len as NativeFn), // table lengths change, so not pure
+        ("assert", Some(1), assert),
+        ("assert_eq", Some(2), assert_eq),
+        ("clock", Some(0), clock),
+        ("println", None, println),
+    ] {
+        natives.register(Native { name: name.to_string(), arity, pure: false, intrinsic: None, f });
+    }
+}
+
+/* ---- shared with the trace executor so both agree on edge cases ---- */
+
+pub(crate) fn num_abs(a: &Value) -> Result<Value, RuntimeError> {
+    match a {
+        Value::Int(n) => Ok(n.checked_abs().map_or(Value::Float((*n as f64).abs()), Value::Int)),
+        _ => Ok(Value::Float(expect_num(a)?.abs())),
+    }
+}
+
+pub(crate) fn num_min(a: &Value, b: &Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Int(x), Value::Int(y)) => Ok(Value::Int(*x.min(y))),
+        _ => Ok(Value::Float(expect_num(a)?.min(expect_num(b)?))),
+    }
+}
+
+pub(crate) fn num_max(a: &Value, b: &Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Int(x), Value::Int(y)) => Ok(Value::Int(*x.max(y))),
+        _ => Ok(Value::Float(expect_num(a)?.max(expect_num(b)?))),
+    }
+}
+
+/// Integer powers stay exact; a float operand, a negative exponent or an
+/// overflow makes it a float power, like `+` does.
+pub(crate) fn num_pow(a: &Value, b: &Value) -> Result<Value, RuntimeError> {
+    if let (Value::Int(x), Value::Int(y)) = (a, b)
+        && let Ok(exp) = u32::try_from(*y)
+        && let Some(n) = x.checked_pow(exp) {
+        return Ok(Value::Int(n));
+    }
+    Ok(Value::Float(expect_num(a)?.powf(expect_num(b)?)))
+}
+
+/* ---- natives ---- */
+
+fn abs(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    num_abs(&args[0])
+}
+
+fn min(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    num_min(&args[0], &args[1])
+}
+
+fn max(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    num_max(&args[0], &args[1])
+}
+
+fn pow(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    num_pow(&args[0], &args[1])
+}
+
+fn assert(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !args[0].is_truthy() {
+        return Err(RuntimeError::Native(String::from("assertion failed")));
+    }
+    Ok(args[0].clone())
+}
+
+fn assert_eq(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    if !values_equal(&args[0], &args[1]) {
+        return Err(RuntimeError::Native(format!("assertion failed: {} != {}", args[0], args[1])));
+    }
+    Ok(Value::Nil)
+}
+
+/// Milliseconds since the VM was created.
+fn clock(vm: &mut VM, _: &[Value]) -> Result<Value, RuntimeError> {
+    Ok(Value::Int(vm.started().elapsed().as_millis() as i64))
+}
+
+/// Length of a string in characters, or of a table's array part.
+fn len(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match &args[0] {
+        Value::Str(s) => Ok(Value::Int(s.chars().count() as i64)),
+        Value::Table(t) => Ok(Value::Int(vm.heap().table(*t).array_len() as i64)),
+        v => Err(RuntimeError::TypeError(format!("attempt to get length of a {} value", v.type_name()))),
+    }
+}
+
+/// `println(fmt, args...)` substitutes each `{}` in the string `fmt` with the
+/// next argument (`{{` and `}}` print braces). Without a format string the
+/// arguments are printed separated by spaces.
+fn println(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Some(Value::Str(fmt)) = args.first() else {
+        let parts: Vec<String> = args.iter().map(|v| v.to_string()).collect();
+        println!("{}", parts.join(" "));
+        return Ok(Value::Nil);
+    };
+    println!("{}", format(fmt, &args[1..])?);
+    Ok(Value::Nil)
+}
+
+fn format(fmt: &str, args: &[Value]) -> Result<String, RuntimeError> {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.next_if_eq(&'{').is_some() => out.push('{'),
+            '}' if chars.next_if_eq(&'}').is_some() => out.push('}'),
+            '{' if chars.next_if_eq(&'}').is_some() => match args.next() {
+                Some(v) => out.push_str(&v.to_string()),
+                None => return Err(RuntimeError::Native(String::from("println: missing argument for {}"))),
+            },
+            c => out.push(c),
+        }
+    }
+    if args.next().is_some() {
+        return Err(RuntimeError::Native(String::from("println: more arguments than {} placeholders")));
+    }
+    Ok(out)
+}
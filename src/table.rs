@@ -0,0 +1,122 @@
+/* ================= Tables ================= */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::gc::GcRef;
+use crate::vm::{RuntimeError, Value};
+
+/// Hashable form of a table key. Floats with an integral value are stored
+/// as ints, so `t[1]` and `t[1.0]` are the same slot.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TableKey {
+    Int(i64),
+    Float(u64),
+    Str(Rc<str>),
+    Bool(bool),
+    /// Tables and functions are keyed by identity.
+    Table(GcRef),
+    Function(GcRef),
+}
+
+impl TableKey {
+    pub fn from_value(v: &Value) -> Result<TableKey, RuntimeError> {
+        Ok(match v {
+            Value::Int(n) => TableKey::Int(*n),
+            Value::Float(x) if x.is_nan() => return Err(RuntimeError::TypeError(String::from("table index is NaN"))),
+            Value::Float(x) if x.fract() == 0.0 && x.abs() < 9.2e18 => TableKey::Int(*x as i64),
+            Value::Float(x) => TableKey::Float(x.to_bits()),
+            Value::Str(s) => TableKey::Str(Rc::clone(s)),
+            Value::Bool(b) => TableKey::Bool(*b),
+            Value::Table(t) => TableKey::Table(*t),
+            Value::Function(f) => TableKey::Function(*f),
+            Value::Nil => return Err(RuntimeError::TypeError(String::from("table index is nil"))),
+        })
+    }
+}
+
+/// A Lua-style table: values for keys `1..=array.len()` live in `array`,
+/// everything else in `hash`.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    // (key, value): the key value is kept so the collector can trace it
+    hash: HashMap<TableKey, (Value, Value)>,
+}
+
+impl Table {
+    pub fn with_capacity(narray: usize, nhash: usize) -> Self {
+        Self { array: Vec::with_capacity(narray), hash: HashMap::with_capacity(nhash) }
+    }
+
+    /// Size of the array part, which is also the length `len` reports.
+    pub fn array_len(&self) -> usize { self.array.len() }
+
+    /// `t[i]` for `1 <= i <= array_len()`.
+    pub fn array_get(&self, i: i64) -> Option<&Value> {
+        usize::try_from(i).ok().and_then(|i| i.checked_sub(1)).and_then(|i| self.array.get(i))
+    }
+
+    /// Stores into the array part; `i` must be in bounds.
+    pub fn array_set(&mut self, i: i64, v: Value) {
+        self.array[i as usize - 1] = v;
+        self.trim();
+    }
+
+    pub fn get(&self, k: &Value) -> Value {
+        let Ok(key) = TableKey::from_value(k) else { return Value::Nil };
+        if let TableKey::Int(i) = key && let Some(v) = self.array_get(i) {
+            return v.clone();
+        }
+        self.hash.get(&key).map_or(Value::Nil, |(_, v)| v.clone())
+    }
+
+    /// `t[k] = v`; assigning nil removes the entry.
+    pub fn set(&mut self, k: &Value, v: Value) -> Result<(), RuntimeError> {
+        let key = TableKey::from_value(k)?;
+        if let TableKey::Int(i) = key {
+            if self.array_get(i).is_some() {
+                self.array_set(i, v);
+                return Ok(());
+            }
+            if i == self.array.len() as i64 + 1 && v != Value::Nil {
+                self.array.push(v);
+                self.migrate();
+                return Ok(());
+            }
+        }
+        if v == Value::Nil {
+            self.hash.remove(&key);
+        } else {
+            self.hash.insert(key, (k.clone(), v));
+        }
+        Ok(())
+    }
+
+    /// Every value the table refers to, keys included, for the collector.
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.array.iter().chain(self.hash.values().flat_map(|(k, v)| [k, v]))
+    }
+
+    /// Moves keys that now continue the array part out of the hash part.
+    fn migrate(&mut self) {
+        while let Some((_, v)) = self.hash.remove(&TableKey::Int(self.array.len() as i64 + 1)) {
+            self.array.push(v);
+        }
+    }
+
+    /// Drops trailing nils so the array part stays a sequence.
+    fn trim(&mut self) {
+        while self.array.last() == Some(&Value::Nil) {
+            self.array.pop();
+        }
+    }
+}
+
+impl fmt::Debug for Table {
+    // tables can contain themselves, so don't recurse into the contents
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Table {{ array: {}, hash: {} }}", self.array.len(), self.hash.len())
+    }
+}
@@ -1,97 +1,826 @@
 /* ================= VM with calls ================= */
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+use std::time::Instant;
 
+use crate::ast::CmpOp;
 use crate::bytecode::BC;
-use crate::codegen::Module;
+use crate::codegen::{FunctionProto, Module};
+use crate::gc::{self, Closure, GcRef, GcStats, Heap, Object, Phase};
+use crate::jit::{ExitFrame, Jit, JitOptions, Recorder, Trace, TraceExit};
+use crate::native::{Native, NativeFn, Natives};
+use crate::stdlib;
+use crate::table::Table;
 
 
 
 #[derive(Clone, Debug)]
-enum Value { Int(i64) }
+pub enum Value { Int(i64), Float(f64), Str(Rc<str>), Table(GcRef), Function(GcRef), Bool(bool), Nil }
 
-pub struct VM<'m> {
-    module: &'m Module,
+/// Structural for scalars and strings, identity for tables and functions.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) | (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Value {
+    /// Only `nil` and `false` are falsy, like Lua; `0` is true.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self { Value::Int(n) => Some(*n), _ => None }
+    }
+
+    /// Numeric value as a float, converting ints.
+    pub fn as_float(&self) -> Option<f64> {
+        match self { Value::Int(n) => Some(*n as f64), Value::Float(x) => Some(*x), _ => None }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{n}"),
+            // Debug keeps the `.0` that tells floats apart from ints
+            Value::Float(x) => write!(f, "{x:?}"),
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Table(t) => write!(f, "table: {:#x}", t.index()),
+            Value::Function(c) => write!(f, "function: {:#x}", c.index()),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+fn arith_error(v: &Value) -> RuntimeError {
+    RuntimeError::TypeError(format!("attempt to perform arithmetic on a {} value", v.type_name()))
+}
+
+/// Numeric operand of an arithmetic op as a float, or the type error the op raises.
+pub(crate) fn expect_num(v: &Value) -> Result<f64, RuntimeError> {
+    v.as_float().ok_or_else(|| arith_error(v))
+}
+
+/// `a + b`: ints stay ints unless the sum overflows, any float operand
+/// (or an overflow) makes the sum a float.
+pub(crate) fn arith_add(a: &Value, b: &Value) -> Result<Value, RuntimeError> {
+    match (a, b) {
+        (Value::Int(x), Value::Int(y)) => Ok(x.checked_add(*y).map_or(Value::Float(*x as f64 + *y as f64), Value::Int)),
+        _ => Ok(Value::Float(expect_num(a)? + expect_num(b)?)),
+    }
+}
+
+pub(crate) fn expect_table(v: &Value) -> Result<GcRef, RuntimeError> {
+    match v {
+        Value::Table(t) => Ok(*t),
+        _ => Err(RuntimeError::TypeError(format!("attempt to index a {} value", v.type_name()))),
+    }
+}
+
+/// `a == b`; numbers compare by value across int and float.
+pub(crate) fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a.as_float(), b.as_float()) {
+        (Some(x), Some(y)) if !(matches!(a, Value::Int(_)) && matches!(b, Value::Int(_))) => x == y,
+        _ => a == b,
+    }
+}
+
+/// Evaluates a comparison. Ordering is defined between numbers and between
+/// strings, equality between any two values.
+pub(crate) fn compare(op: CmpOp, a: &Value, b: &Value) -> Result<bool, RuntimeError> {
+    use std::cmp::Ordering;
+    let ord = match op {
+        CmpOp::Eq => return Ok(values_equal(a, b)),
+        CmpOp::Ne => return Ok(!values_equal(a, b)),
+        _ => match (a, b) {
+            (Value::Int(x), Value::Int(y)) => Some(x.cmp(y)),
+            (Value::Str(x), Value::Str(y)) => Some(x.cmp(y)),
+            _ => match (a.as_float(), b.as_float()) {
+                (Some(x), Some(y)) => x.partial_cmp(&y),
+                _ => return Err(RuntimeError::TypeError(
+                    format!("attempt to compare {} with {}", a.type_name(), b.type_name()))),
+            },
+        },
+    };
+    // NaN is unordered, every ordering test on it is false
+    Ok(ord.is_some_and(|o| match op {
+        CmpOp::Lt => o == Ordering::Less,
+        CmpOp::Le => o != Ordering::Greater,
+        CmpOp::Gt => o == Ordering::Greater,
+        _ => o != Ordering::Less,
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    UndefinedFunction(String),
+    ArityMismatch { name: String, expected: usize, got: usize },
+    StackUnderflow,
+    TypeError(String),
+    /// Calls nested deeper than the VM's max depth.
+    StackOverflow(usize),
+    /// Raised by a native function.
+    Native(String),
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::UndefinedFunction(name) => write!(f, "undefined function: {name}"),
+            RuntimeError::ArityMismatch { name, expected, got } =>
+                write!(f, "arity mismatch for {name}: expected {expected} arguments, got {got}"),
+            RuntimeError::StackUnderflow => write!(f, "stack underflow"),
+            RuntimeError::TypeError(msg) => write!(f, "type error: {msg}"),
+            RuntimeError::StackOverflow(depth) => write!(f, "stack overflow: more than {depth} nested calls"),
+            RuntimeError::Native(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// Variables of one call. Captured variables live in heap cells instead of
+/// `vars`, so the closures capturing them see every later assignment.
+#[derive(Default)]
+pub(crate) struct Frame {
+    vars: HashMap<String, Value>,
+    cells: HashMap<String, GcRef>,
+    /// Arguments past the parameters of a variadic function, for `...`.
+    varargs: Vec<Value>,
+    /// Whether the call returns only its first result, as a tail call
+    /// followed by a Ret does.
+    first_only: bool,
+}
+
+/// Nested calls a VM allows unless told otherwise; see [`VM::set_max_depth`].
+pub const DEFAULT_MAX_DEPTH: usize = 100_000;
+
+/// A suspended caller in the interpreter loop: the function it runs (`None`
+/// for the code the loop started with), where it goes on, and its stack base.
+struct CallInfo {
+    proto: Option<Rc<FunctionProto>>,
+    ip: usize,
+    base: usize,
+}
+
+pub struct VM {
+    module: Rc<Module>,
     // Call stack of environments (lexical locals). Simple: HashMap per frame.
-    env_stack: Vec<HashMap<String, Value>>,
+    // The bottom frame holds the globals.
+    env_stack: Vec<Frame>,
+    // Operand stack shared by all active calls; each run_code owns the part
+    // above the length it started at.
+    stack: Vec<Value>,
+    natives: Natives,
+    jit: Jit,
+    max_depth: usize,
+    heap: Heap,
+    started: Instant,
+    // Every string value is interned here, so equal strings share one allocation.
+    strings: HashSet<Rc<str>>,
+    // All results of the call that returned last; the call pushed the first,
+    // a Results op right after it takes the rest from here.
+    rets: Vec<Value>,
+    // Length of the open list on top of the stack, for the op after it.
+    open: Option<usize>,
+    // Values held outside the VM that a collection must keep: the arguments
+    // of running natives and what running traces hold.
+    roots: Vec<Value>,
 }
 
-impl<'m> VM<'m> {
-    pub fn new(module: &'m Module) -> Self {
-        Self { module, env_stack: vec![HashMap::new()] }
+impl VM {
+    pub fn new(module: Rc<Module>) -> Self {
+        Self::with_options(module, JitOptions::default())
+    }
+
+    pub fn with_options(module: Rc<Module>, opts: JitOptions) -> Self {
+        let mut natives = Natives::default();
+        stdlib::open(&mut natives);
+        Self {
+            module,
+            env_stack: vec![Frame::default()],
+            stack: Vec::new(),
+            natives,
+            jit: Jit::new(opts),
+            max_depth: DEFAULT_MAX_DEPTH,
+            heap: Heap::default(),
+            started: Instant::now(),
+            strings: HashSet::new(),
+            rets: Vec::new(),
+            open: None,
+            roots: Vec::new(),
+        }
+    }
+
+    pub fn module(&self) -> &Module { &self.module }
+
+    /// Swaps in a new module. Globals survive, compiled traces do not.
+    pub fn set_module(&mut self, module: Rc<Module>) {
+        self.module = module;
+        self.jit = Jit::new(self.jit.opts.clone());
+    }
+
+    pub fn started(&self) -> Instant { self.started }
+
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(rc) = self.strings.get(s) { return Rc::clone(rc); }
+        let rc: Rc<str> = Rc::from(s);
+        self.strings.insert(Rc::clone(&rc));
+        self.heap.add_debt(1);
+        rc
+    }
+
+    /// `a .. b`: strings and numbers concatenate into a new string.
+    pub(crate) fn concat(&mut self, a: &Value, b: &Value) -> Result<Value, RuntimeError> {
+        for v in [a, b] {
+            if !matches!(v, Value::Str(_) | Value::Int(_) | Value::Float(_)) {
+                return Err(RuntimeError::TypeError(format!("attempt to concatenate a {} value", v.type_name())));
+            }
+        }
+        Ok(Value::Str(self.intern(&format!("{a}{b}"))))
+    }
+
+    /// The operand `n` values below the top of the stack.
+    pub(crate) fn peek(&self, n: usize) -> &Value { &self.stack[self.stack.len() - 1 - n] }
+
+    pub fn heap(&self) -> &Heap { &self.heap }
+    pub fn heap_mut(&mut self) -> &mut Heap { &mut self.heap }
+    pub fn gc_stats(&self) -> &GcStats { self.heap.stats() }
+
+    pub(crate) fn new_table(&mut self, t: Table) -> Value { Value::Table(self.heap.alloc(Object::Table(t))) }
+
+    /// Closure over the prototype `name`, capturing its upvalues from the
+    /// current frame.
+    pub(crate) fn new_closure(&mut self, name: &str) -> Value {
+        let proto = Rc::clone(&self.module.funs[name]);
+        let upvals = proto.upvals.iter().map(|u| self.capture(u)).collect();
+        Value::Function(self.heap.alloc(Object::Closure(Closure { proto, upvals })))
+    }
+
+    /// Cell for the variable `name` of the current frame, moving the
+    /// variable into one on its first capture.
+    fn capture(&mut self, name: &str) -> GcRef {
+        let frame = self.env_stack.last_mut().unwrap();
+        if let Some(&c) = frame.cells.get(name) { return c; }
+        let v = frame.vars.remove(name).unwrap_or(Value::Nil);
+        let c = self.heap.alloc(Object::Cell(v));
+        self.env_stack.last_mut().unwrap().cells.insert(name.to_string(), c);
+        c
+    }
+
+    /// `t[k]`; missing keys read as nil.
+    pub(crate) fn index(&self, t: &Value, k: &Value) -> Result<Value, RuntimeError> {
+        Ok(self.heap.table(expect_table(t)?).get(k))
+    }
+
+    /// `t[k] = v`.
+    pub(crate) fn set_index(&mut self, t: &Value, k: &Value, v: Value) -> Result<(), RuntimeError> {
+        let t = expect_table(t)?;
+        self.heap.barrier(t);
+        self.heap.table_mut(t).set(k, v)
+    }
+
+    /// Collector step at a safe point: every live value is in `stack` or
+    /// `env_stack` here, so those are all the roots there are.
+    pub(crate) fn gc_check(&mut self) {
+        if self.heap.wants_step() { self.gc_step(gc::STEP_WORK); }
+    }
+
+    /// Like [`VM::gc_check`], keeping `live` values the VM does not hold.
+    pub(crate) fn gc_check_with<'a>(&mut self, live: impl Iterator<Item = &'a Value>) {
+        if self.heap.wants_step() { self.with_roots(live, |vm| vm.gc_step(gc::STEP_WORK)); }
+    }
+
+    /// Runs `f` with `live` values the VM does not hold kept alive by any
+    /// collection it triggers.
+    pub(crate) fn with_roots<'a, R>(&mut self, live: impl Iterator<Item = &'a Value>, f: impl FnOnce(&mut VM) -> R) -> R {
+        let base = self.roots.len();
+        self.roots.extend(live.cloned());
+        let r = f(self);
+        self.roots.truncate(base);
+        r
     }
 
-    fn with_frame<F: FnOnce(&mut VM) -> Value>(&mut self, f: F) -> Value {
-        self.env_stack.push(HashMap::new());
+    fn gc_step(&mut self, work: usize) {
+        match self.heap.phase() {
+            Phase::Idle => {
+                self.heap.start();
+                self.mark_roots();
+            }
+            Phase::Mark => {
+                if self.heap.propagate(work) {
+                    // roots are not behind a barrier, scan them again atomically
+                    self.mark_roots();
+                    self.heap.finish_mark();
+                }
+            }
+            Phase::Sweep(_) => {
+                if self.heap.sweep(work) { self.sweep_strings(); }
+            }
+        }
+    }
+
+    fn mark_roots(&mut self) {
+        let frames = self.env_stack.iter().flat_map(|f| f.vars.values().chain(&f.varargs));
+        for v in self.stack.iter().chain(&self.rets).chain(&self.roots).chain(frames) {
+            self.heap.mark(v);
+        }
+        for &c in self.env_stack.iter().flat_map(|f| f.cells.values()) {
+            self.heap.mark_ref(c);
+        }
+    }
+
+    /// Drops interned strings only the intern table still refers to.
+    fn sweep_strings(&mut self) {
+        let before = self.strings.len();
+        self.strings.retain(|s| Rc::strong_count(s) > 1);
+        self.heap.note_strings_freed(before - self.strings.len());
+    }
+
+    /// Finishes the cycle in progress, if any, then runs a full one.
+    pub fn collect_garbage(&mut self) {
+        while self.heap.phase() != Phase::Idle { self.gc_step(usize::MAX); }
+        self.gc_step(usize::MAX);
+        while self.heap.phase() != Phase::Idle { self.gc_step(usize::MAX); }
+    }
+
+    pub fn jit(&self) -> &Jit { &self.jit }
+    pub fn jit_mut(&mut self) -> &mut Jit { &mut self.jit }
+
+    pub fn natives(&self) -> &Natives { &self.natives }
+
+    pub fn max_depth(&self) -> usize { self.max_depth }
+
+    /// Limits how deeply calls may nest before one fails with
+    /// [`RuntimeError::StackOverflow`]. Tail calls do not nest.
+    pub fn set_max_depth(&mut self, depth: usize) { self.max_depth = depth; }
+
+    pub fn register_native(&mut self, name: &str, arity: Option<usize>, f: NativeFn) {
+        self.natives.register(Native { name: name.to_string(), arity, pure: false, intrinsic: None, f });
+    }
+
+    /// Registers a native the JIT may constant-fold and CSE; see [`Native::pure`].
+    pub fn register_pure_native(&mut self, name: &str, arity: Option<usize>, f: NativeFn) {
+        self.natives.register(Native { name: name.to_string(), arity, pure: true, intrinsic: None, f });
+    }
+
+    pub fn global(&self, k: &str) -> Option<Value> { self.env_stack[0].vars.get(k).cloned() }
+    pub fn set_global(&mut self, k: &str, v: Value) {
+        self.env_stack[0].vars.insert(k.to_string(), v);
+    }
+
+    fn with_frame<F: FnOnce(&mut VM) -> Result<Value, RuntimeError>>(&mut self, frame: Frame, f: F) -> Result<Value, RuntimeError> {
+        self.push_frame(frame)?;
         let ret = f(self);
         self.env_stack.pop();
         ret
     }
 
-    fn set(&mut self, k: &str, v: Value) {
-        self.env_stack.last_mut().unwrap().insert(k.to_string(), v);
+    /// A frame for a call to `f`, with its upvalue cells in place.
+    pub(crate) fn closure_frame(&self, f: GcRef) -> Frame {
+        let c = self.heap.closure(f);
+        Frame { cells: c.proto.upvals.iter().cloned().zip(c.upvals.iter().copied()).collect(), ..Frame::default() }
+    }
+
+    /// Enters the frame of a call, unless calls already nest `max_depth` deep.
+    pub(crate) fn push_frame(&mut self, frame: Frame) -> Result<(), RuntimeError> {
+        // the bottom frame holds the globals, not a call
+        if self.env_stack.len() > self.max_depth { return Err(RuntimeError::StackOverflow(self.max_depth)); }
+        self.env_stack.push(frame);
+        Ok(())
+    }
+    pub(crate) fn pop_frame(&mut self) { self.env_stack.pop(); }
+    /// Puts `frame` in place of the current one for a tail call, which
+    /// returns only its first result if `first_only` or the caller's call did.
+    pub(crate) fn swap_frame(&mut self, frame: Frame, first_only: bool) {
+        let top = self.env_stack.last_mut().unwrap();
+        *top = Frame { first_only: first_only || top.first_only, ..frame };
+    }
+    pub(crate) fn frame_depth(&self) -> usize { self.env_stack.len() }
+    pub(crate) fn truncate_frames(&mut self, depth: usize) { self.env_stack.truncate(depth); }
+
+    pub(crate) fn set(&mut self, k: &str, v: Value) {
+        let frame = self.env_stack.last_mut().unwrap();
+        match frame.cells.get(k) {
+            Some(&c) => {
+                self.heap.barrier(c);
+                *self.heap.cell_mut(c) = v;
+            }
+            None => { frame.vars.insert(k.to_string(), v); }
+        }
+    }
+    /// A variable of the current frame, else a global. Variables of callers
+    /// are out of scope; those a closure captured reach it through its cells.
+    pub(crate) fn get(&self, k: &str) -> Value {
+        let frames = [self.env_stack.last().unwrap(), &self.env_stack[0]];
+        for frame in frames.into_iter().take(self.env_stack.len()) {
+            if let Some(v) = frame.vars.get(k) { return v.clone(); }
+            if let Some(&c) = frame.cells.get(k) { return self.heap.cell(c).clone(); }
+        }
+        Value::Nil
     }
-    fn get(&self, k: &str) -> Value {
-        for frame in self.env_stack.iter().rev() {
-            if let Some(v) = frame.get(k) { return v.clone(); }
+
+    /// Calls `name`: a function value in a variable of that name, else the
+    /// script function, else the native.
+    pub fn call_function(&mut self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        match self.callee(name) {
+            Some((proto, frame)) => self.call_proto(&proto, frame, args),
+            None => self.call_native(name, &args),
         }
-        Value::Int(0)
     }
 
-    fn call_function(&mut self, name: &str, args: Vec<Value>) -> Value {
-        let proto = self.module.funs.get(name)
-            .unwrap_or_else(|| panic!("undefined function: {}", name));
-        if proto.params.len() != args.len() { panic!("arity mismatch for {}", name); }
+    /// The script function a call to `name` runs, with a frame for it;
+    /// `None` leaves the call to the natives.
+    fn callee(&self, name: &str) -> Option<(Rc<FunctionProto>, Frame)> {
+        if let Value::Function(f) = self.get(name) { return Some(self.closure_callee(f)); }
+        self.module.funs.get(name).map(|p| (Rc::clone(p), Frame::default()))
+    }
 
-        self.with_frame(|vm| {
-            for (p, v) in proto.params.iter().zip(args.into_iter()) {
-                vm.set(p, v);
-            }
-            vm.run_code(&proto.code)
+    fn closure_callee(&self, f: GcRef) -> (Rc<FunctionProto>, Frame) {
+        (Rc::clone(&self.heap.closure(f).proto), self.closure_frame(f))
+    }
+
+    fn check_arity(proto: &FunctionProto, got: usize) -> Result<(), RuntimeError> {
+        if proto.params.len() != got && !(proto.variadic && got > proto.params.len()) {
+            return Err(RuntimeError::ArityMismatch { name: proto.name.clone(), expected: proto.params.len(), got });
+        }
+        Ok(())
+    }
+
+    fn call_proto(&mut self, proto: &FunctionProto, frame: Frame, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        Self::check_arity(proto, args.len())?;
+        self.with_frame(frame, |vm| {
+            vm.bind_args(proto, args);
+            vm.enter(proto)
         })
     }
 
-    fn run_code(&mut self, code: &[BC]) -> Value {
+    /// Binds `args` in the frame just entered: to the parameters, and what
+    /// is left over to `...`.
+    fn bind_args(&mut self, proto: &FunctionProto, mut args: Vec<Value>) {
+        let varargs = args.split_off(proto.params.len());
+        for (p, v) in proto.params.iter().zip(args) {
+            self.set(p, v);
+        }
+        self.env_stack.last_mut().unwrap().varargs = varargs;
+    }
+
+    /// Makes `v` the only result of the call that returned last.
+    pub(crate) fn set_result(&mut self, v: &Value) {
+        self.rets.clear();
+        self.rets.push(v.clone());
+    }
+
+    /// Makes `vals` the results of the call returning from the current frame.
+    pub(crate) fn set_results(&mut self, vals: Vec<Value>) {
+        self.rets = vals;
+        if self.env_stack.last().unwrap().first_only { self.rets.truncate(1); }
+    }
+
+    pub fn call_native(&mut self, name: &str, args: &[Value]) -> Result<Value, RuntimeError> {
+        let native = self.natives.get(name)
+            .ok_or_else(|| RuntimeError::UndefinedFunction(name.to_string()))?;
+        if let Some(arity) = native.arity && arity != args.len() {
+            return Err(RuntimeError::ArityMismatch { name: name.to_string(), expected: arity, got: args.len() });
+        }
+        let f = native.f;
+        // the arguments are off the stack, so root them while the native runs
+        let v = self.with_roots(args.iter(), |vm| f(vm, args))?;
+        self.set_result(&v);
+        Ok(v)
+    }
+
+    /// Runs `proto` in the current frame: through its trace if one is compiled,
+    /// otherwise in the interpreter, recording a trace once the function is hot.
+    fn enter(&mut self, proto: &FunctionProto) -> Result<Value, RuntimeError> {
+        if let Some(trace) = self.jit.trace(&proto.name) { return self.run_trace(&trace, &[]); }
+        let unroll = self.jit.opts.max_unroll;
+        let mut rec = if self.jit.tick(&proto.name) { Some(self.jit.started(Recorder::new(&proto.name, unroll))) } else { None };
+        let base = self.stack.len();
+        self.run_code(&proto.code, 0, base, &mut rec)
+    }
+
+    /// Runs `trace` to the end of the call it covers; `stack` is what an
+    /// up-recursion trace starts with.
+    fn run_trace(&mut self, trace: &Trace, stack: &[Value]) -> Result<Value, RuntimeError> {
+        match trace.execute(self, stack)? {
+            TraceExit::Return(v) => Ok(v),
+            // a guard failed: finish the call in the interpreter
+            TraceExit::Side(frames) => self.resume(frames),
+        }
+    }
+
+    /// Finishes a call in the interpreter after its trace left through a
+    /// side exit. Each exit frame but the outermost is an inlined call whose
+    /// VM frame the trace pushed; it runs to its return, and the result
+    /// goes onto the stack of the frame that called it.
+    fn resume(&mut self, frames: Vec<ExitFrame>) -> Result<Value, RuntimeError> {
+        // the exit frames' stacks are all the trace leaves live, so once they
+        // are back on the VM stack collecting is safe again
+        let outer = self.stack.len();
+        let mut bases = Vec::with_capacity(frames.len());
+        for f in &frames {
+            bases.push(self.stack.len());
+            self.stack.extend(f.stack.iter().cloned());
+        }
+        self.gc_check();
+
+        let mut ret = Value::Nil;
+        for (i, f) in frames.iter().enumerate().rev() {
+            if i + 1 < frames.len() { self.stack.push(ret); }
+            // returning from recursion is what up-recursion traces are for
+            let r = if frames.get(i + 1).is_some_and(|callee| callee.func == f.func) {
+                self.run_up(&f.func, f.pc, bases[i])
+            } else {
+                let module = Rc::clone(&self.module);
+                self.run_code(&module.funs[&f.func].code, f.pc, bases[i], &mut None)
+            };
+            if i > 0 { self.env_stack.pop(); }
+            ret = match r {
+                Ok(v) => v,
+                Err(e) => {
+                    // frames of the inlined calls still waiting for a result
+                    let keep = self.env_stack.len() - i.saturating_sub(1);
+                    self.env_stack.truncate(keep);
+                    self.stack.truncate(outer);
+                    return Err(e);
+                }
+            };
+        }
+        Ok(ret)
+    }
+
+    /// Runs `func` from `pc`, where a recursive call just returned to it, with
+    /// the stack from `base` on: through its up-recursion trace if there is
+    /// one, otherwise in the interpreter, recording one once it is hot.
+    fn run_up(&mut self, func: &str, pc: usize, base: usize) -> Result<Value, RuntimeError> {
+        let name = format!("{func}@{pc}");
+        if let Some(trace) = self.jit.trace(&name) {
+            let stack = self.stack.split_off(base);
+            return self.run_trace(&trace, &stack);
+        }
+        let unroll = self.jit.opts.max_unroll;
+        let mut rec = if self.jit.tick(&name) {
+            Some(self.jit.started(Recorder::new_up(&name, func, pc, &self.stack[base..], unroll)))
+        } else {
+            None
+        };
+        let module = Rc::clone(&self.module);
+        self.run_code(&module.funs[func].code, pc, base, &mut rec)
+    }
+
+    /// Interprets `code` from `ip`, with the operand stack starting at `base`.
+    fn run_code(&mut self, code: &[BC], ip: usize, base: usize, rec: &mut Option<Recorder>) -> Result<Value, RuntimeError> {
+        let depth = self.env_stack.len();
+        let ret = self.interpret(code, ip, base, rec);
+        // an error leaves the frames of the calls it unwound behind
+        if ret.is_err() { self.env_stack.truncate(depth); }
+        self.stack.truncate(base);
+        ret
+    }
+
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError::StackUnderflow)
+    }
+
+    /// Script calls run in this loop, on an explicit stack of suspended
+    /// callers, so call depth does not use up the host stack.
+    fn interpret(&mut self, root: &[BC], mut ip: usize, mut base: usize, rec: &mut Option<Recorder>) -> Result<Value, RuntimeError> {
         use BC::*;
-        let mut stack: Vec<Value> = Vec::new();
-        let mut ip = 0usize;
+        let mut cur: Option<Rc<FunctionProto>> = None;
+        let mut calls: Vec<CallInfo> = Vec::new();
         loop {
+            let proto = cur.clone();
+            let code = proto.as_deref().map_or(root, |p| &p.code[..]);
             let op = &code[ip];
+            if let Some(r) = rec.as_mut() {
+                if let Err(reason) = r.record(code, ip, self) {
+                    self.jit.abort(rec.take().unwrap(), reason);
+                }
+                // complete once the traced function returns or loops
+                if let Some(r) = rec.take_if(|r| r.done()) { self.jit.finish(r); }
+            }
             match op {
-                LoadConst(n) => stack.push(Value::Int(*n)),
-                LoadVar(name) => stack.push(self.get(name)),
+                LoadConst(n) => self.stack.push(Value::Int(*n)),
+                LoadFloat(x) => self.stack.push(Value::Float(*x)),
+                LoadStr(s) => {
+                    let s = self.intern(s);
+                    self.stack.push(Value::Str(s));
+                }
+                LoadBool(b) => self.stack.push(Value::Bool(*b)),
+                LoadNil => self.stack.push(Value::Nil),
+                LoadVar(name) => {
+                    let v = self.get(name);
+                    self.stack.push(v);
+                }
                 StoreVar(name) => {
-                    let v = stack.pop().expect("stack underflow");
+                    let v = self.pop()?;
+                    self.set(name, v);
+                }
+                Pop => { self.pop()?; }
+                StoreLoad(name) => {
+                    let v = self.stack.last().ok_or(RuntimeError::StackUnderflow)?.clone();
                     self.set(name, v);
                 }
+                LoadVarLoadVar(a, b) => {
+                    let (a, b) = (self.get(a), self.get(b));
+                    self.stack.extend([a, b]);
+                }
+                AddConst(n) => {
+                    let a = self.pop()?;
+                    self.stack.push(arith_add(&a, &Value::Int(*n))?);
+                }
                 Add => {
-                    let b = match stack.pop().expect("stack underflow") { Value::Int(n) => n };
-                    let a = match stack.pop().expect("stack underflow") { Value::Int(n) => n };
-                    stack.push(Value::Int(a + b));
-                }
-                Call(fname, argc) => {
-                    let mut args = Vec::with_capacity(*argc);
-                    for _ in 0..*argc {
-                        args.push(stack.pop().expect("stack underflow"));
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(arith_add(&a, &b)?);
+                }
+                Concat => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    let v = self.concat(&a, &b)?;
+                    self.stack.push(v);
+                    self.gc_check();
+                }
+                Compare(cmp) => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.stack.push(Value::Bool(compare(*cmp, &a, &b)?));
+                }
+                NewTable(n) => {
+                    let items = self.open.take().unwrap_or(0);
+                    if self.stack.len() - base < 2 * n + items { return Err(RuntimeError::StackUnderflow); }
+                    let items = self.stack.split_off(self.stack.len() - items);
+                    let entries = self.stack.split_off(self.stack.len() - 2 * n);
+                    let mut t = Table::with_capacity(*n + items.len(), 0);
+                    for kv in entries.chunks(2) {
+                        t.set(&kv[0], kv[1].clone())?;
                     }
-                    args.reverse();
-                    let ret = self.call_function(fname, args);
-                    stack.push(ret);
+                    for (i, v) in items.into_iter().enumerate() {
+                        t.set(&Value::Int((n + i + 1) as i64), v)?;
+                    }
+                    let t = self.new_table(t);
+                    self.stack.push(t);
+                    self.gc_check();
+                }
+                GetIndex => {
+                    let k = self.pop()?;
+                    let t = self.pop()?;
+                    let v = self.index(&t, &k)?;
+                    self.stack.push(v);
+                }
+                SetIndex => {
+                    let v = self.pop()?;
+                    let k = self.pop()?;
+                    let t = self.pop()?;
+                    self.set_index(&t, &k, v)?;
+                }
+                Not => {
+                    let v = self.pop()?;
+                    self.stack.push(Value::Bool(!v.is_truthy()));
+                }
+                JumpIfFalseOrPop(target) | JumpIfTrueOrPop(target) => {
+                    let v = self.stack.last().ok_or(RuntimeError::StackUnderflow)?;
+                    if v.is_truthy() == matches!(op, JumpIfTrueOrPop(_)) {
+                        ip = *target;
+                        continue;
+                    }
+                    self.stack.pop();
+                }
+                Call(_, argc) | CallValue(argc) | TailCall(_, argc) | TailCallValue(argc) => {
+                    let argc = *argc + self.open.take().unwrap_or(0);
+                    let on_stack = usize::from(matches!(op, CallValue(_) | TailCallValue(_)));
+                    if self.stack.len() - base < argc + on_stack { return Err(RuntimeError::StackUnderflow); }
+                    let args = self.stack.split_off(self.stack.len() - argc);
+                    let callee = match op {
+                        Call(name, _) | TailCall(name, _) => self.callee(name).ok_or(name),
+                        _ => {
+                            let callee = self.pop()?;
+                            let Value::Function(f) = callee else {
+                                return Err(RuntimeError::TypeError(format!("attempt to call a {} value", callee.type_name())));
+                            };
+                            Ok(self.closure_callee(f))
+                        }
+                    };
+                    let ret = match callee {
+                        Err(name) => self.call_native(name, &args)?,
+                        Ok((proto, frame)) => {
+                            Self::check_arity(&proto, args.len())?;
+                            // while recording, calls are recorded inline instead
+                            let trace = if rec.is_none() { self.jit.trace(&proto.name) } else { None };
+                            match trace {
+                                Some(trace) => {
+                                    self.push_frame(frame)?;
+                                    self.bind_args(&proto, args);
+                                    let v = self.run_trace(&trace, &[])?;
+                                    self.env_stack.pop();
+                                    v
+                                }
+                                None => {
+                                    if matches!(op, TailCall(..) | TailCallValue(_)) {
+                                        // the callee returns straight to our caller
+                                        self.swap_frame(frame, matches!(code.get(ip + 1), Some(Ret)));
+                                        self.stack.truncate(base);
+                                    } else {
+                                        self.push_frame(frame)?;
+                                        calls.push(CallInfo { proto: cur.take(), ip: ip + 1, base });
+                                        base = self.stack.len();
+                                    }
+                                    self.bind_args(&proto, args);
+                                    if rec.is_none() && self.jit.tick(&proto.name) {
+                                        *rec = Some(self.jit.started(Recorder::new(&proto.name, self.jit.opts.max_unroll)));
+                                    }
+                                    cur = Some(proto);
+                                    ip = 0;
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+                    // a tail call that ran to completion here is followed by its Ret
+                    self.stack.push(ret);
+                    // also where a trace that returned normally gets its allocations paid for
+                    self.gc_check();
+                }
+                Closure(name) => {
+                    let f = self.new_closure(name);
+                    self.stack.push(f);
+                    self.gc_check();
                 }
                 Print => {
-                    match stack.pop().expect("stack underflow") {
-                        Value::Int(n) => println!("{n}"),
+                    let v = self.pop()?;
+                    println!("{v}");
+                }
+                Results(want) => {
+                    // the call pushed its first result already
+                    self.pop()?;
+                    let n = want.unwrap_or(self.rets.len());
+                    for i in 0..n {
+                        let v = self.rets.get(i).cloned().unwrap_or(Value::Nil);
+                        self.stack.push(v);
                     }
+                    if want.is_none() { self.open = Some(n); }
                 }
-                Ret => {
-                    return stack.pop().unwrap_or(Value::Int(0));
+                Varargs(want) => {
+                    let varargs = &self.env_stack.last().unwrap().varargs;
+                    let n = want.unwrap_or(varargs.len());
+                    let vals: Vec<Value> = (0..n).map(|i| varargs.get(i).cloned().unwrap_or(Value::Nil)).collect();
+                    self.stack.extend(vals);
+                    if want.is_none() { self.open = Some(n); }
+                }
+                Ret | RetN(_) => {
+                    if let RetN(n) = op {
+                        let n = *n + self.open.take().unwrap_or(0);
+                        if self.stack.len() - base < n { return Err(RuntimeError::StackUnderflow); }
+                        let at = self.stack.len() - n;
+                        let vals = self.stack.split_off(at);
+                        self.set_results(vals);
+                    } else {
+                        let v = if self.stack.len() > base { self.pop()? } else { Value::Nil };
+                        self.set_result(&v);
+                    }
+                    let v = self.rets.first().cloned().unwrap_or(Value::Nil);
+                    let Some(caller) = calls.pop() else { return Ok(v) };
+                    self.env_stack.pop();
+                    self.stack.truncate(base);
+                    (cur, ip, base) = (caller.proto, caller.ip, caller.base);
+                    self.stack.push(v);
+                    self.gc_check();
+                    continue;
                 }
             }
             ip += 1;
         }
     }
 
-    pub fn run_main(&mut self) {
-        let _ = self.run_code(&self.module.main.code);
+    pub fn run_main(&mut self) -> Result<Value, RuntimeError> {
+        let module = Rc::clone(&self.module);
+        let base = self.stack.len();
+        self.run_code(&module.main.code, 0, base, &mut None)
     }
-}
\ No newline at end of file
+}
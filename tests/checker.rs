@@ -0,0 +1,26 @@
+use tiny_jit::Engine;
+
+fn warnings(src: &str) -> Vec<String> {
+    Engine::new().check(src).unwrap().iter().map(|d| d.to_string()).collect()
+}
+
+#[test]
+fn unreachable_code_is_reported_once_per_body() {
+    let src = "fn f() {\n    return 1;\n    return 2;\n    print 3;\n}\n";
+    assert_eq!(warnings(src), ["line 3: warning: unreachable code after `return`"]);
+}
+
+#[test]
+fn unreachable_code_after_a_returning_block() {
+    let src = "fn f() {\n    { return 1; }\n    print 2;\n    { return 3; print 4; }\n}\n";
+    assert_eq!(warnings(src), [
+        "line 3: warning: unreachable code after `return`",
+        "line 4: warning: unreachable code after `return`",
+    ]);
+}
+
+#[test]
+fn unreachable_warning_points_at_a_dead_block_statement() {
+    let src = "fn f() {\n    return 1;\n    {\n        print 2;\n    }\n}\n";
+    assert_eq!(warnings(src), ["line 4: warning: unreachable code after `return`"]);
+}
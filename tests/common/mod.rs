@@ -0,0 +1,31 @@
+//! Helpers shared by the tests that run the command line program.
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// What a run of the program printed.
+pub struct Run {
+    pub ok: bool,
+    /// Standard output after the bytecode listing.
+    pub output: String,
+    pub stderr: String,
+}
+
+/// Runs the program `src` with the command line `flags`.
+pub fn run(src: &str, flags: &[&str]) -> Run {
+    static N: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!("tiny-jit-{}-{}.tj", std::process::id(), N.fetch_add(1, Ordering::Relaxed)));
+    std::fs::write(&path, src).unwrap();
+    let run = run_file(&path, flags);
+    std::fs::remove_file(&path).unwrap();
+    run
+}
+
+pub fn run_file(path: &PathBuf, flags: &[&str]) -> Run {
+    let out = Command::new(env!("CARGO_BIN_EXE_tiny-jit")).args(flags).arg(path).output().unwrap();
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let output = stdout.split_once("== Program output ==\n").map_or("", |(_, o)| o).to_string();
+    Run { ok: out.status.success(), output, stderr: String::from_utf8(out.stderr).unwrap() }
+}
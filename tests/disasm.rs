@@ -0,0 +1,85 @@
+use tiny_jit::Engine;
+use tiny_jit::disasm::disassemble;
+
+const SRC: &str = "\
+fn pick(c, a, b) {
+    return c and a or b;
+}
+fn two(a) { return a, a + 1; }
+fn count(...) { return len({...}); }
+fn f(x) {
+    print count(x, two(x));
+    return two(x);
+}
+";
+
+const LISTING: &str = "\
+main
+; 1: fn pick(c, a, b) {
+0000    0  Closure pick
+0001    1  StoreVar pick
+; 4: fn two(a) { return a, a + 1; }
+0002    0  Closure two
+0003    1  StoreVar two
+; 5: fn count(...) { return len({...}); }
+0004    0  Closure count
+0005    1  StoreVar count
+; 6: fn f(x) {
+0006    0  Closure f
+0007    1  StoreVar f
+0008    0  LoadConst 0
+0009    1  Ret
+
+fn pick(c, a, b) ; line 1
+; 2: return c and a or b;
+0000    0  LoadVar c
+0001    1  JumpIfFalseOrPop 0003
+0002    0  LoadVar a
+0003    1  JumpIfTrueOrPop 0005
+0004    0  LoadVar b
+0005    1  Ret
+
+fn two(a) ; line 4
+; 4: fn two(a) { return a, a + 1; }
+0000    0  LoadVarLoadVar a a
+0001    2  AddConst 1
+0002    2  RetN 2
+
+fn count(...) ; line 5
+; 5: fn count(...) { return len({...}); }
+0000    0  Varargs *
+0001  0+?  NewTable 0
+0002    1  TailCall len 1
+0003    1  Results *
+0004  0+?  RetN 0
+
+fn f(x) ; line 6
+; 7: print count(x, two(x));
+0000    0  LoadVarLoadVar x x
+0001    2  Call two 1
+0002    2  Results *
+0003  1+?  Call count 1
+0004    1  Print
+; 8: return two(x);
+0005    0  LoadVar x
+0006    1  TailCall two 1
+0007    1  Results *
+0008  0+?  RetN 0
+";
+
+#[test]
+fn listing_matches_golden() {
+    let mut engine = Engine::new();
+    engine.set_optimize(false);
+    let module = engine.compile(SRC).unwrap();
+    assert_eq!(disassemble(&module, Some(SRC)), LISTING);
+}
+
+#[test]
+fn pc_to_line_table() {
+    let module = Engine::new().compile(SRC).unwrap();
+    let f = module.main.children.iter().find(|c| c.name == "f").unwrap();
+    assert_eq!(f.line, 6);
+    assert_eq!(f.lines, [7, 7, 7, 7, 7, 8, 8, 8, 8]);
+    assert_eq!(module.main.lines[..2], [1, 1]);
+}
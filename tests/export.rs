@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+use tiny_jit::export::{ir_to_dot, ir_to_json, module_to_json, trace_to_json};
+use tiny_jit::ir::IR;
+use tiny_jit::Engine;
+
+/* ---- a small JSON reader, to check the output is well-formed ---- */
+
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    List(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    fn parse(src: &str) -> Json {
+        let mut chars = src.chars().peekable();
+        let v = Self::value(&mut chars);
+        assert_eq!(chars.next(), None, "trailing text");
+        v
+    }
+
+    fn value(c: &mut std::iter::Peekable<std::str::Chars>) -> Json {
+        fn word(c: &mut std::iter::Peekable<std::str::Chars>, w: &str, v: Json) -> Json {
+            for ch in w.chars() { assert_eq!(c.next(), Some(ch)); }
+            v
+        }
+        match c.peek().copied().expect("value expected") {
+            'n' => word(c, "null", Json::Null),
+            't' => word(c, "true", Json::Bool(true)),
+            'f' => word(c, "false", Json::Bool(false)),
+            '"' => Json::Str(Self::string(c)),
+            '[' => {
+                c.next();
+                let mut items = Vec::new();
+                if c.peek() == Some(&']') { c.next(); return Json::List(items); }
+                loop {
+                    items.push(Self::value(c));
+                    match c.next() { Some(',') => {}, Some(']') => return Json::List(items), t => panic!("unexpected {t:?}") }
+                }
+            }
+            '{' => {
+                c.next();
+                let mut map = BTreeMap::new();
+                if c.peek() == Some(&'}') { c.next(); return Json::Object(map); }
+                loop {
+                    let k = Self::string(c);
+                    assert_eq!(c.next(), Some(':'));
+                    map.insert(k, Self::value(c));
+                    match c.next() { Some(',') => {}, Some('}') => return Json::Object(map), t => panic!("unexpected {t:?}") }
+                }
+            }
+            _ => {
+                let mut s = String::new();
+                while let Some(&ch) = c.peek() && (ch.is_ascii_digit() || "+-.eE".contains(ch)) { s.push(ch); c.next(); }
+                Json::Num(s.parse().unwrap_or_else(|_| panic!("bad number {s:?}")))
+            }
+        }
+    }
+
+    fn string(c: &mut std::iter::Peekable<std::str::Chars>) -> String {
+        assert_eq!(c.next(), Some('"'));
+        let mut s = String::new();
+        loop {
+            match c.next().expect("unterminated string") {
+                '"' => return s,
+                '\\' => match c.next().unwrap() {
+                    'n' => s.push('\n'),
+                    't' => s.push('\t'),
+                    'r' => s.push('\r'),
+                    'u' => {
+                        let hex: String = c.by_ref().take(4).collect();
+                        s.push(char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    }
+                    ch => s.push(ch),
+                },
+                ch if ch < ' ' => panic!("raw control character in a string"),
+                ch => s.push(ch),
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> &Json {
+        let Json::Object(map) = self else { panic!("not an object: {self:?}") };
+        map.get(key).unwrap_or_else(|| panic!("no {key}"))
+    }
+
+    fn strs(&self) -> Vec<String> {
+        let Json::List(items) = self else { panic!("not a list: {self:?}") };
+        items.iter().map(|i| match i { Json::Str(s) => s.clone(), _ => panic!("not a string: {i:?}") }).collect()
+    }
+}
+
+fn fixture() -> IR {
+    IR::parse(include_str!("ir/add.ir")).unwrap()
+}
+
+#[test]
+fn dot_has_data_flow_and_skip_chain_edges() {
+    let dot = ir_to_dot(&fixture(), "add");
+    assert!(dot.starts_with("digraph \"add\" {\n"));
+    for edge in [
+        // operands
+        "r0 -> r2 [label=\"a\"];", "r1 -> r2 [label=\"b\"];", "r2 -> r3 [label=\"b\"];",
+        "r2 -> r5 [label=\"a\"];", "r4 -> r5 [label=\"b\"];", "r5 -> r6 [label=\"a\"];",
+        // skip chains: the second LoadVar and AddOv point back at the first
+        "r1 -> r0 [style=dashed, color=gray, constraint=false];",
+        "r5 -> r2 [style=dashed, color=gray, constraint=false];",
+        // snapshots
+        "snap2 [shape=note, label=\"snap 0002 add pc=1 [r0 r1]\"];", "r2 -> snap2 [style=dotted, label=\"exit\"];",
+    ] {
+        assert!(dot.contains(edge), "no {edge} in\n{dot}");
+    }
+    assert_eq!(dot.matches("->").count(), 6 + 2 + 4);
+}
+
+#[test]
+fn ir_json_round_trips_the_instructions() {
+    let ir = fixture();
+    let json = Json::parse(&ir_to_json(&ir));
+    let Json::List(code) = json.get("code") else { panic!() };
+    assert_eq!(code.len(), ir.code.len());
+    assert_eq!(code[2], Json::parse(r#"{"op":"AddOv","type":"Int","a":0,"b":1,"prev_same_op":null}"#));
+    assert_eq!(code[3], Json::parse(r#"{"op":"StoreVar","type":"Any","a":{"sym":"k"},"b":2,"prev_same_op":null}"#));
+    assert_eq!(code[4], Json::parse(r#"{"op":"KInt","type":"Int","a":{"const":1},"b":null,"prev_same_op":null}"#));
+    assert_eq!(code[5].get("prev_same_op"), &Json::Num(2.0));
+    let Json::List(snaps) = json.get("snapshots") else { panic!() };
+    assert_eq!(snaps[2], Json::parse(r#"{"ins":2,"func":"add","pc":1,"stack":[0,1],"callers":[]}"#));
+}
+
+#[test]
+fn trace_and_module_json_round_trip_their_fields() {
+    let src = "fn add(a, b) { return a + b; }\nfn outer(s) {\n    let f = fn(x) { return x .. \"\\\"q\\\"\\n\"; };\n    return f(s);\n}\nx = add(1, 2);\n";
+    let mut engine = Engine::new();
+    engine.set_optimize(false);
+    let module = engine.compile(src).unwrap();
+    let json = Json::parse(&module_to_json(&module));
+
+    let main = json.get("main");
+    assert_eq!(main.get("name"), &Json::Str(String::from("main")));
+    assert_eq!(main.get("code").strs(), module.main.code.iter().map(|op| op.to_string()).collect::<Vec<_>>());
+    let Json::Object(funs) = json.get("functions") else { panic!() };
+    assert_eq!(funs.keys().collect::<Vec<_>>(), { let mut k: Vec<_> = module.funs.keys().collect(); k.sort(); k });
+    for (name, f) in &module.funs {
+        let j = &funs[name];
+        assert_eq!(j.get("name"), &Json::Str(f.name.clone()));
+        assert_eq!(j.get("params").strs(), f.params);
+        assert_eq!(j.get("upvals").strs(), f.upvals);
+        assert_eq!(j.get("variadic"), &Json::Bool(f.variadic));
+        assert_eq!(j.get("line"), &Json::Num(f.line as f64));
+        assert_eq!(j.get("code").strs(), f.code.iter().map(|op| op.to_string()).collect::<Vec<_>>());
+        assert_eq!(j.get("lines"), &Json::List(f.lines.iter().map(|&l| Json::Num(l as f64)).collect()));
+        assert_eq!(j.get("children").strs(), f.children.iter().map(|c| c.name.clone()).collect::<Vec<_>>());
+    }
+
+    engine.load(module);
+    for _ in 0..12 { engine.call("add", &[1, 2]).unwrap(); }
+    let trace = engine.vm().jit().trace("add").unwrap();
+    let json = Json::parse(&trace_to_json(&trace));
+    assert_eq!(json.get("name"), &Json::Str(String::from("add")));
+    assert_eq!(json.get("func"), &Json::Str(String::from("add")));
+    assert_eq!(json.get("pc"), &Json::Num(0.0));
+    assert_eq!(json.get("ir"), &Json::parse(&ir_to_json(&trace.ir)));
+}
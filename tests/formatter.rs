@@ -0,0 +1,50 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tiny_jit::formatter::{format, FormatOptions};
+
+fn programs() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// What running the program at `path` prints, without the bytecode listing.
+fn output(path: &Path) -> String {
+    let out = Command::new(env!("CARGO_BIN_EXE_tiny-jit")).arg(path).output().unwrap();
+    assert!(out.status.success(), "{} failed: {}", path.display(), String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    stdout.split_once("== Program output ==").expect("no program output").1.to_string()
+}
+
+#[test]
+fn formatting_is_idempotent() {
+    for opts in [FormatOptions::default(), FormatOptions { indent: String::from("\t") }] {
+        for path in programs() {
+            let once = format(&std::fs::read_to_string(&path).unwrap(), &opts).unwrap();
+            assert_eq!(format(&once, &opts).unwrap(), once, "{}", path.display());
+        }
+    }
+}
+
+#[test]
+fn formatted_programs_print_the_same() {
+    let dir = std::env::temp_dir().join(format!("tiny-jit-fmt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for path in programs() {
+        let formatted = dir.join(path.file_name().unwrap());
+        std::fs::write(&formatted, format(&std::fs::read_to_string(&path).unwrap(), &FormatOptions::default()).unwrap()).unwrap();
+        assert_eq!(output(&formatted), output(&path), "{}", path.display());
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn function_literals_in_tables_get_a_line_each() {
+    let src = "t = { a, fn(x) { return x; } };\nf = fn() { /* empty */ };\n";
+    let expected = "t = {\n    a,\n    fn(x) {\n        return x;\n    }\n};\nf = fn() { /* empty */ };\n";
+    assert_eq!(format(src, &FormatOptions::default()).unwrap(), expected);
+}
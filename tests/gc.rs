@@ -0,0 +1,31 @@
+use tiny_jit::{Engine, RuntimeError, Value};
+use tiny_jit::vm::VM;
+
+/// Collects everything unreachable, then sums the array parts of its
+/// table arguments.
+fn collect_then_sum(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    vm.collect_garbage();
+    let mut sum = 0;
+    for arg in args {
+        let Value::Table(t) = arg else { return Err(RuntimeError::Native(String::from("expected a table"))) };
+        let t = vm.heap().table(*t);
+        sum += (1..=t.array_len() as i64).filter_map(|i| t.array_get(i)?.as_int()).sum::<i64>();
+    }
+    Ok(Value::Int(sum))
+}
+
+#[test]
+fn native_arguments_survive_a_collection() {
+    let mut engine = Engine::new();
+    engine.register_native("collect_then_sum", None, collect_then_sum);
+    engine.eval("x = collect_then_sum({1, 2}, {3, 4});").unwrap();
+    assert_eq!(engine.global("x"), Some(10));
+}
+
+#[test]
+fn trace_values_survive_a_collection_in_a_native() {
+    let mut engine = Engine::new();
+    engine.register_native("collect_then_sum", None, collect_then_sum);
+    engine.eval("fn f(n) { let t = {n, n}; let s = collect_then_sum({n}); return t[1] + t[2] + s; }").unwrap();
+    for n in 0..20 { assert_eq!(engine.call("f", &[n]).unwrap(), 3 * n); }
+}
@@ -0,0 +1,59 @@
+use tiny_jit::{Engine, Value};
+use tiny_jit::ir::IR;
+
+/// Programs with a function `f` that gets traced once called with `args`
+/// often enough, covering the kinds of IR the recorder emits.
+const PROGRAMS: &[(&str, &[i64])] = &[
+    ("fn f(x, y) { k = x + y; return pow(x, y) + k; }", &[2, 3]),
+    ("fn f(x) { return abs(x) + min(x, 3) + max(x, 3); }", &[2]),
+    ("fn f(x) { return \"n=\" .. x; }", &[2]),
+    ("fn f(n) { let t = {n, n}; t[1] = t[2] + 1; t.k = 1.5; return t[1] + t.k; }", &[2]),
+    ("fn f(n) { let g = fn(x) { return x + n; }; return g(1) + len({n}); }", &[2]),
+    ("fn f(n) { return n == 30 and n or f(n + 1); }", &[2]),
+    ("fn f(n) { return n >= 30 and 1 or 1 + f(n + 1); }", &[2]),
+    ("fn f(n) { println(\"{}\", n); return n; }", &[2]),
+];
+
+/// The text of every trace running `src` makes.
+fn traces(src: &str, args: &[i64]) -> Vec<String> {
+    let mut engine = Engine::new();
+    engine.eval(src).unwrap();
+    let args: Vec<Value> = args.iter().map(|&n| Value::Int(n)).collect();
+    for _ in 0..12 { engine.call_value("f", args.clone()).unwrap(); }
+    engine.vm().jit().traces().map(|t| t.ir.to_string()).collect()
+}
+
+#[test]
+fn traces_round_trip_through_text() {
+    for (src, args) in PROGRAMS {
+        let texts = traces(src, args);
+        assert!(!texts.is_empty(), "no trace for {src}");
+        for text in texts {
+            let ir = IR::parse(&text).unwrap_or_else(|e| panic!("{e} in\n{text}"));
+            assert_eq!(ir.to_string(), text);
+        }
+    }
+}
+
+#[test]
+fn trace_matches_fixture() {
+    let expected = IR::parse(include_str!("ir/add.ir")).unwrap();
+    let mut engine = Engine::new();
+    engine.eval(include_str!("ir/add.tj")).unwrap();
+    let trace = engine.vm().jit().trace("add").expect("add was not traced");
+    assert_eq!(trace.ir.to_string(), expected.to_string());
+    assert_eq!(trace.ir.snapshots, expected.snapshots);
+}
+
+#[test]
+fn parse_errors_name_the_line() {
+    for (src, line, msg) in [
+        ("0000 Frob Int r0", 1, "unknown op `Frob`"),
+        ("0000 AddOv Int r1 r0", 1, "r1 does not come before its use"),
+        ("; comment\n0000 KInt Int #1\n0002 Ret Any r0", 3, "instruction 0002 where 0001 is next"),
+        ("0000 KInt Int #1\nsnap 0000 f pc=x []", 2, "expected pc=number, got `pc=x`"),
+    ] {
+        let Err(e) = IR::parse(src) else { panic!("{src:?} parsed") };
+        assert_eq!((e.line, e.msg.as_str()), (line, msg), "for {src:?}");
+    }
+}
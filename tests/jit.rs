@@ -0,0 +1,115 @@
+use tiny_jit::vm::VM;
+use tiny_jit::{Engine, Error, JitOptions, RuntimeError, Value};
+
+#[test]
+fn pow_overflow_after_trace_is_hot() {
+    let mut engine = Engine::new();
+    engine.eval("fn f(x, y) { return pow(x, y) + 1; }").unwrap();
+    for _ in 0..12 { assert_eq!(engine.call("f", &[2, 3]).unwrap(), 9); }
+    assert_eq!(engine.call_value("f", vec![Value::Int(2), Value::Int(100)]).unwrap(), Value::Float(2f64.powi(100) + 1.0));
+    assert_eq!(engine.call_value("f", vec![Value::Int(2), Value::Int(3)]).unwrap(), Value::Int(9));
+}
+
+#[test]
+fn abs_overflow_after_trace_is_hot() {
+    let mut engine = Engine::new();
+    engine.eval("fn g(x) { return abs(x) + 1; }").unwrap();
+    for _ in 0..12 { assert_eq!(engine.call("g", &[3]).unwrap(), 4); }
+    assert_eq!(engine.call_value("g", vec![Value::Int(i64::MIN)]).unwrap(), Value::Float(2f64.powi(63) + 1.0));
+}
+
+#[test]
+fn hot_allocating_loop_is_collected() {
+    let mut engine = Engine::new();
+    engine.set_max_depth(1_000_000);
+    engine.eval("fn spin(n) { let t = {n, n}; return n == 300000 and n or spin(n + 1); }").unwrap();
+    assert_eq!(engine.call("spin", &[0]).unwrap(), 300000);
+    let stats = engine.gc_stats();
+    assert!(stats.cycles > 0, "no collection cycle ran");
+    assert!(stats.live < 10_000, "{} objects still live", stats.live);
+}
+
+/// An int for small arguments, else a bool.
+fn int_or_bool(_: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    match args[0] {
+        Value::Int(n) if n < 20 => Ok(Value::Int(n)),
+        _ => Ok(Value::Bool(true)),
+    }
+}
+
+#[test]
+fn unused_ops_that_fail_still_raise_their_error() {
+    // the value past the one `x` takes is dropped, so nothing uses it
+    let src = "\
+fn add(n) { let x = 0, int_or_bool(n) + 1; return x; }
+fn cat(n) { let x = 0, int_or_bool(n) .. \"s\"; return x; }
+fn cmp(n) { let x = 0, int_or_bool(n) < 1; return x; }
+";
+    for jit in [false, true] {
+        let mut engine = Engine::with_options(JitOptions { enabled: jit, ..JitOptions::default() });
+        engine.register_native("int_or_bool", Some(1), int_or_bool);
+        engine.eval(src).unwrap();
+        for name in ["add", "cat", "cmp"] {
+            for n in 0..12 { assert_eq!(engine.call(name, &[n]).unwrap(), 0, "{name}({n})"); }
+            let e = engine.call(name, &[100]);
+            assert!(matches!(e, Err(Error::Runtime(RuntimeError::TypeError(_)))), "{name} with jit {jit}: {e:?}");
+        }
+    }
+}
+
+#[test]
+fn calls_in_parens_and_after_and_or_return_one_result() {
+    let src = "\
+fn two(n) { return n, 2; }
+fn paren(n) { return (two(n)); }
+fn both(n) { return n > 0 and two(n); }
+fn either(n) { return n == 0 or two(n); }
+fn relay(n) { return two(n); }
+fn via_relay(n) { return n == 0 or relay(n); }
+fn second(n) { let a, b = (two(n)); return b; }
+";
+    for jit in [false, true] {
+        let mut engine = Engine::with_options(JitOptions { enabled: jit, ..JitOptions::default() });
+        engine.eval(src).unwrap();
+        for name in ["paren", "both", "either", "via_relay"] {
+            engine.eval(&format!("fn second_of_{name}(n) {{ let a, b = {name}(n); return b; }}")).unwrap();
+            for n in 1..13 {
+                assert_eq!(engine.call(name, &[n]).unwrap(), n, "{name}({n}) with jit {jit}");
+                assert_eq!(engine.call_value(&format!("second_of_{name}"), vec![Value::Int(n)]).unwrap(), Value::Nil, "{name}({n}) with jit {jit}");
+            }
+        }
+        for n in 1..13 { assert_eq!(engine.call_value("second", vec![Value::Int(n)]).unwrap(), Value::Nil); }
+    }
+}
+
+#[test]
+fn tail_calls_run_in_constant_frame_depth() {
+    let src = "\
+fn spin(i, n) { return i == n and i or spin(i + 1, n); }
+fn ping(i, n) { return i == n and i or pong(i + 1, n); }
+fn pong(i, n) { return ping(i + 1, n); }
+";
+    for jit in [false, true] {
+        let mut engine = Engine::with_options(JitOptions { enabled: jit, ..JitOptions::default() });
+        // far fewer frames than the loops take steps
+        engine.set_max_depth(16);
+        engine.eval(src).unwrap();
+        for name in ["spin", "ping"] {
+            assert_eq!(engine.call(name, &[0, 200_000]).unwrap(), 200_000, "{name} with jit {jit}");
+        }
+    }
+}
+
+#[test]
+fn deep_recursion_is_a_stack_overflow_error() {
+    let src = "fn down(n) { return n == 0 and 0 or 1 + down(n + 1); }";
+    for jit in [false, true] {
+        for depth in [100, 100_000] {
+            let mut engine = Engine::with_options(JitOptions { enabled: jit, ..JitOptions::default() });
+            engine.set_max_depth(depth);
+            engine.eval(src).unwrap();
+            let e = engine.call("down", &[1]);
+            assert!(matches!(e, Err(Error::Runtime(RuntimeError::StackOverflow(d))) if d == depth), "depth {depth} with jit {jit}: {e:?}");
+        }
+    }
+}
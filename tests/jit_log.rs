@@ -0,0 +1,48 @@
+mod common;
+
+const SRC: &str = "\
+fn spin(n) { return n == 40 and n or spin(n + 1); }
+fn va(...) { return 1; }
+fn g(x) { return va(x); }
+print spin(0);
+print g(1); print g(1); print g(1); print g(1); print g(1); print g(1);
+print g(1); print g(1); print g(1); print g(1); print g(1); print g(1);
+";
+
+#[test]
+fn verbose_logs_start_stop_and_abort() {
+    let run = common::run(SRC, &["-jv"]);
+    assert!(run.ok, "{}", run.stderr);
+    let lines: Vec<&str> = run.stderr.lines().collect();
+    assert_eq!(lines, [
+        "[TRACE start spin pc=0]",
+        "[TRACE stop spin pc=0 -> loop, 12 IR]",
+        "[TRACE start g pc=0]",
+        "[TRACE abort g pc=0 -- NYI: call to variadic va]",
+        "[TRACE start va pc=0]",
+        "[TRACE stop va pc=0 -> return, 2 IR]",
+    ]);
+    assert_eq!(run.output, format!("40\n{}", "1\n".repeat(12)));
+}
+
+#[test]
+fn dump_lists_every_pass_and_the_snapshots() {
+    let run = common::run(SRC, &["-jdump"]);
+    assert!(run.ok, "{}", run.stderr);
+    let spin = run.stderr.split("---- TRACE spin stop").next().unwrap();
+    for section in [
+        "---- TRACE spin start spin pc=0\n",
+        "---- TRACE spin bytecode\n0000 LoadVar n ",
+        "---- TRACE spin IR recorded\n0000 LoadVar Int @n\n",
+        "---- TRACE spin IR after DCE\n0000 LoadVar Int @n\n",
+        "0006 AddOv Int r0 r5\n",
+        "0011 Loop Any\n---- TRACE spin snapshots\nsnap 0000 spin pc=0 []\nsnap 0003 spin pc=3 [r2]\n",
+        "---- TRACE spin mcode\n",
+    ] {
+        assert!(spin.contains(section), "no {section:?} in\n{spin}");
+    }
+    assert!(run.stderr.contains("---- TRACE spin stop -> loop\n"));
+    assert!(run.stderr.contains("---- TRACE g bytecode, aborted\n0000 LoadVar x "));
+    // without -jv, the abort itself is not logged
+    assert!(!run.stderr.contains("[TRACE"));
+}
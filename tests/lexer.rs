@@ -0,0 +1,89 @@
+use tiny_jit::lexer::{Lexer, Span, Token};
+
+fn tokens(src: &str) -> Vec<Token> {
+    Lexer::new(src).map(|(t, _)| t).collect()
+}
+
+fn span(start: usize, end: usize, line: usize) -> Span {
+    Span { start, end, line }
+}
+
+fn error(msg: &str) -> Token {
+    Token::Error(msg.to_string())
+}
+
+#[test]
+fn out_of_range_integer_is_an_error_token_with_its_span() {
+    let toks: Vec<_> = Lexer::new("x = 99999999999999999999;").collect();
+    assert_eq!(toks, [
+        (Token::Ident(String::from("x")), span(0, 1, 1)),
+        (Token::Assign, span(2, 3, 1)),
+        (error("integer literal 99999999999999999999 out of range"), span(4, 24, 1)),
+        (Token::Semicolon, span(24, 25, 1)),
+    ]);
+}
+
+#[test]
+fn unknown_characters_are_errors_and_lexing_goes_on() {
+    let toks: Vec<_> = Lexer::new("a\n@ # b").collect();
+    assert_eq!(toks, [
+        (Token::Ident(String::from("a")), span(0, 1, 1)),
+        (error("unexpected character '@'"), span(2, 3, 2)),
+        (error("unexpected character '#'"), span(4, 5, 2)),
+        (Token::Ident(String::from("b")), span(6, 7, 2)),
+    ]);
+}
+
+#[test]
+fn number_literals() {
+    assert_eq!(tokens("0x1F 0XfF 0b1010 0B1 1_000_000 0x_ff_ 1.5 2e3 1.5E-2 3.x"), [
+        Token::Number(31), Token::Number(255), Token::Number(10), Token::Number(1), Token::Number(1_000_000),
+        Token::Number(255), Token::Float(1.5), Token::Float(2000.0), Token::Float(0.015),
+        Token::Number(3), Token::Dot, Token::Ident(String::from("x")),
+    ]);
+    assert_eq!(tokens("0x 0b102 12ab 0x8000000000000000"), [
+        error("malformed number \"0x\""),
+        error("malformed number \"0b102\""),
+        error("malformed number \"12ab\""),
+        error("integer literal 0x8000000000000000 out of range"),
+    ]);
+}
+
+#[test]
+fn block_comments_do_not_nest() {
+    assert_eq!(tokens("a /* x /* y */ b"), [Token::Ident(String::from("a")), Token::Ident(String::from("b"))]);
+    assert_eq!(tokens("/* x /* y */ z */"), [
+        Token::Ident(String::from("z")),
+        error("unexpected character '*'"),
+        error("unexpected character '/'"),
+    ]);
+}
+
+#[test]
+fn unterminated_comment_and_string() {
+    let toks: Vec<_> = Lexer::new("a /* never\nclosed").collect();
+    assert_eq!(toks, [
+        (Token::Ident(String::from("a")), span(0, 1, 1)),
+        (error("unterminated comment"), span(2, 17, 1)),
+    ]);
+    assert_eq!(tokens("\"abc"), [error("unterminated string")]);
+}
+
+#[test]
+fn string_escapes() {
+    assert_eq!(tokens(r#""\x41\x7f\u{e9}\u{1F600}\n\t\r\0\\\"""#), [Token::Str(String::from("A\x7f\u{e9}\u{1F600}\n\t\r\0\\\""))]);
+}
+
+#[test]
+fn bad_escapes_are_errors_for_the_whole_string() {
+    assert_eq!(tokens(r#""\x80" "\x4" "\xzz" "\u{110000}" "\u{}" "\u41" "\q" x"#), [
+        error("invalid escape: \\x needs two hex digits below 80"),
+        error("invalid escape: \\x needs two hex digits below 80"),
+        error("invalid escape: \\x needs two hex digits below 80"),
+        error("invalid escape: \\u needs {hex digits} of a character"),
+        error("invalid escape: \\u needs {hex digits} of a character"),
+        error("invalid escape: \\u needs {hex digits} of a character"),
+        error("invalid escape \\q"),
+        Token::Ident(String::from("x")),
+    ]);
+}
@@ -0,0 +1,95 @@
+mod common;
+
+use tiny_jit::vm::VM;
+use tiny_jit::{Engine, RuntimeError, Value};
+
+/// Runs `src` with and without the AST optimizer and checks both print
+/// `expected`.
+fn same(src: &str, expected: &str) {
+    let on = common::run(src, &["-O1"]);
+    let off = common::run(src, &["-O0"]);
+    assert!(on.ok && off.ok, "failed:\n{}\n{}", on.stderr, off.stderr);
+    assert_eq!(off.output, expected, "without the optimizer");
+    assert_eq!(on.output, expected, "with the optimizer");
+}
+
+#[test]
+fn closure_writes_a_propagated_local_before_a_call() {
+    same("\
+fn f() {
+    let x = 1;
+    let set = fn() { x = 2; return 0; };
+    set();
+    return x;
+}
+print f();
+", "2\n");
+}
+
+/// Sets the global `g`, behind the back of the script calling it.
+fn set_g(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    vm.set_global("g", args[0].clone());
+    Ok(Value::Nil)
+}
+
+#[test]
+fn global_changed_inside_a_called_function() {
+    let src = "g = 1;\nfn f() { set_g(2); return g; }\nset_g(5);\nx = g;\ny = f();\n";
+    for optimize in [false, true] {
+        let mut engine = Engine::new();
+        engine.set_optimize(optimize);
+        engine.register_native("set_g", Some(1), set_g);
+        engine.eval(src).unwrap();
+        assert_eq!((engine.global("x"), engine.global("y")), (Some(5), Some(2)), "optimize: {optimize}");
+    }
+}
+
+#[test]
+fn table_changed_inside_a_called_function() {
+    same("\
+t = {1};
+fn bump() { t[1] = 5; return 0; }
+x = t[1];
+bump();
+print t[1] + x;
+", "6\n");
+}
+
+#[test]
+fn repeated_name_in_a_multiple_assignment() {
+    same("x, x = 1, 2;\nprint x;\nfn f() { let y = 0; y, y = 3, 4; return y; }\nprint f();\n", "1\n3\n");
+}
+
+#[test]
+fn let_shadowing_an_inlinable_function() {
+    same("\
+fn dbl(a) { return a + a; }
+fn f() {
+    let dbl = fn(a) { return a; };
+    return dbl(4);
+}
+print f();
+print dbl(4);
+", "4\n8\n");
+}
+
+#[test]
+fn call_argument_to_a_function_reading_a_parameter_twice() {
+    same("\
+c = {0};
+fn next() { c[1] = c[1] + 1; return c[1]; }
+fn dbl(a) { return a + a; }
+print dbl(next());
+print c[1];
+", "2\n1\n");
+}
+
+#[test]
+fn int_overflow_folds_to_float() {
+    same("\
+fn big() { return 9223372036854775807; }
+x = 9223372036854775807 + 1;
+print x;
+print big() + 1;
+", "9.223372036854776e18\n9.223372036854776e18\n");
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use tiny_jit::bytecode::BC;
+use tiny_jit::codegen::{peephole, FunctionProto};
+use tiny_jit::{Engine, Module, Value};
+
+fn var(name: &str) -> String { name.to_string() }
+
+fn listing(code: &[BC]) -> Vec<String> {
+    code.iter().map(|op| op.to_string()).collect()
+}
+
+/// `code` run as main with the globals `vars` set, and then global `r`.
+fn run(code: Vec<BC>, vars: &[(&str, Value)]) -> Option<Value> {
+    let lines = vec![1; code.len()];
+    let main = FunctionProto { name: var("main"), params: Vec::new(), variadic: false, upvals: Vec::new(), code, lines, line: 0, children: Vec::new() };
+    let mut engine = Engine::new();
+    for (name, v) in vars { engine.vm_mut().set_global(name, v.clone()); }
+    engine.load(Module { funs: HashMap::new(), main });
+    engine.run_main().unwrap();
+    engine.vm().global("r")
+}
+
+/// Checks that `code` becomes `expected` and does the same, run with each of `inputs`.
+fn check(code: Vec<BC>, expected: &[&str], inputs: &[&[(&str, Value)]]) {
+    let (fused, lines) = peephole(code.clone(), (1..=code.len()).collect());
+    assert_eq!(listing(&fused), expected);
+    assert_eq!(lines.len(), fused.len());
+    for vars in inputs {
+        assert_eq!(run(fused.clone(), vars), run(code.clone(), vars), "with {vars:?}");
+    }
+}
+
+#[test]
+fn pairs_are_fused() {
+    check(vec![
+        BC::LoadVar(var("a")), BC::LoadVar(var("b")), BC::Add,
+        BC::StoreVar(var("x")), BC::LoadVar(var("x")),
+        BC::LoadConst(1), BC::Add,
+        BC::StoreVar(var("r")), BC::LoadConst(0), BC::Ret,
+    ], &[
+        "LoadVarLoadVar a b", "Add", "StoreLoad x", "AddConst 1", "StoreVar r", "LoadConst 0", "Ret",
+    ], &[&[("a", Value::Int(2)), ("b", Value::Int(3))]]);
+}
+
+#[test]
+fn fused_instructions_take_the_line_of_the_first() {
+    let code = vec![BC::LoadVar(var("a")), BC::LoadVar(var("b")), BC::Add, BC::LoadConst(1), BC::Add, BC::Ret];
+    let (_, lines) = peephole(code, vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(lines, [1, 3, 4, 6]);
+}
+
+#[test]
+fn no_pair_a_jump_lands_inside_is_fused() {
+    // r = x + (c or 10): the Add is where `or` jumps to
+    check(vec![
+        BC::LoadVar(var("x")), BC::LoadVar(var("c")),
+        BC::JumpIfTrueOrPop(4),
+        BC::LoadConst(10),
+        BC::Add,
+        BC::StoreVar(var("r")), BC::LoadConst(0), BC::Ret,
+    ], &[
+        "LoadVarLoadVar x c", "JumpIfTrueOrPop 0003", "LoadConst 10", "Add", "StoreVar r", "LoadConst 0", "Ret",
+    ], &[&[("x", Value::Int(1)), ("c", Value::Int(5))], &[("x", Value::Int(1)), ("c", Value::Nil)]]);
+}
+
+#[test]
+fn code_after_a_return_stays_where_a_jump_lands() {
+    check(vec![
+        BC::LoadVar(var("c")),
+        BC::JumpIfTrueOrPop(6),
+        BC::LoadConst(1), BC::StoreVar(var("r")), BC::LoadConst(0), BC::Ret,
+        BC::StoreVar(var("r")), BC::LoadConst(0), BC::Ret,
+        // nothing lands here
+        BC::LoadConst(99), BC::StoreVar(var("r")), BC::LoadConst(0), BC::Ret,
+    ], &[
+        "LoadVar c", "JumpIfTrueOrPop 0006", "LoadConst 1", "StoreVar r", "LoadConst 0", "Ret",
+        "StoreVar r", "LoadConst 0", "Ret",
+    ], &[&[("c", Value::Int(7))], &[("c", Value::Bool(false))]]);
+}
+
+#[test]
+fn jumps_onto_jumps_of_their_kind_are_threaded() {
+    // r = (a and b) and c, with the inner `and` jumping onto the outer one
+    check(vec![
+        BC::LoadVar(var("a")),
+        BC::JumpIfFalseOrPop(3),
+        BC::LoadVar(var("b")),
+        BC::JumpIfFalseOrPop(5),
+        BC::LoadVar(var("c")),
+        BC::StoreVar(var("r")), BC::LoadConst(0), BC::Ret,
+    ], &[
+        "LoadVar a", "JumpIfFalseOrPop 0005", "LoadVar b", "JumpIfFalseOrPop 0005", "LoadVar c",
+        "StoreVar r", "LoadConst 0", "Ret",
+    ], &[
+        &[("a", Value::Int(1)), ("b", Value::Int(2)), ("c", Value::Int(3))],
+        &[("a", Value::Bool(false)), ("b", Value::Int(2)), ("c", Value::Int(3))],
+        &[("a", Value::Int(1)), ("b", Value::Nil), ("c", Value::Int(3))],
+    ]);
+}